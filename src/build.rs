@@ -17,6 +17,14 @@ pub struct General {
 	pub no_auto_unpack: bool,
 	#[serde(default)]
 	pub recurse_submodules: bool,
+	// overrides the shell of the config for the commands in string form
+	pub shell: Option<String>,
+	// runs the steps in the source dir instead of the build dir
+	#[serde(default)]
+	pub build_in_source: bool,
+	// copies the source to the build dir before each step for packages that only build in-tree
+	#[serde(default)]
+	pub copy_source_to_build: bool,
 	#[serde(default)]
 	pub exports_aclocal: bool,
 	#[serde(default)]
@@ -25,17 +33,165 @@ pub struct General {
 	pub depends: Vec<String>,
 	#[serde(default)]
 	pub host_depends: Vec<String>,
+	pub source_date_epoch: Option<u64>,
+	// sha256 of the downloaded archives by their file name
+	#[serde(default)]
+	pub checksums: HashMap<String, String>,
 	pub template: Option<String>,
+	// overrides the series file in the patches dir
+	pub patches: Option<Vec<PatchEntry>>,
 	#[serde(flatten)]
 	pub others: HashMap<String, String>
 }
 
-#[derive(Deserialize, Debug, Default)]
+fn default_strip() -> u32 {
+	1
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum PatchEntry {
+	File(String),
+	Options {
+		file: String,
+		#[serde(default = "default_strip")]
+		strip: u32,
+		#[serde(default)]
+		versions: Vec<String>,
+		#[serde(default)]
+		targets: Vec<String>
+	}
+}
+
+// the parts of a shell command are joined with spaces, an argv is run as it is without a shell
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Command {
+	Shell(Vec<String>),
+	Exec {
+		argv: Vec<String>
+	}
+}
+
+impl Command {
+	// how the command is shown in errors
+	pub fn display(&self) -> String {
+		match self {
+			Command::Shell(parts) => parts.join(" "),
+			Command::Exec { argv } => argv.join(" ")
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvOp {
+	Set {
+		name: String,
+		value: String
+	},
+	Append {
+		name: String,
+		value: String,
+		separator: String
+	},
+	Prepend {
+		name: String,
+		value: String,
+		separator: String
+	},
+	Unset {
+		name: String
+	}
+}
+
+impl EnvOp {
+	pub fn value_mut(&mut self) -> Option<&mut String> {
+		match self {
+			EnvOp::Set { value, .. } | EnvOp::Append { value, .. } | EnvOp::Prepend { value, .. } => Some(value),
+			EnvOp::Unset { .. } => None
+		}
+	}
+}
+
+// an entry of an env list, either a table of variables that are set or a single operation like
+// { append = "PATH", value = "/opt/bin", separator = ":" }
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct EnvEntry {
+	pub ops: Vec<EnvOp>
+}
+
+impl TryFrom<HashMap<String, String>> for EnvEntry {
+	type Error = String;
+
+	fn try_from(mut map: HashMap<String, String>) -> Result<Self, String> {
+		let kinds: Vec<_> = ["set", "append", "prepend", "unset"].into_iter()
+			.filter(|kind| map.contains_key(*kind))
+			.collect();
+
+		let kind = match kinds[..] {
+			[] => {
+				// the variables of a table don't depend on each other, they're sorted to keep the env stable
+				let mut ops: Vec<_> = map.into_iter().collect();
+				ops.sort();
+				return Ok(EnvEntry {
+					ops: ops.into_iter().map(|(name, value)| EnvOp::Set { name, value }).collect()
+				});
+			},
+			[kind] => kind,
+			_ => return Err(format!("an env entry can only have one of {}", kinds.join(", ")))
+		};
+
+		let name = map.remove(kind).unwrap();
+		let value = map.remove("value");
+		let separator = map.remove("separator");
+		if let Some(key) = map.keys().next() {
+			return Err(format!("unknown key {} in the env entry for {}", key, name));
+		}
+
+		if kind == "unset" {
+			if value.is_some() || separator.is_some() {
+				return Err(format!("unset of {} takes no value or separator", name));
+			}
+			return Ok(EnvEntry { ops: vec![EnvOp::Unset { name }] });
+		}
+
+		let Some(value) = value else {
+			return Err(format!("{} of {} needs a value", kind, name));
+		};
+		let op = match (kind, separator) {
+			("set", None) => EnvOp::Set { name, value },
+			("set", Some(_)) => return Err(format!("set of {} takes no separator", name)),
+			("append", separator) => EnvOp::Append { name, value, separator: separator.unwrap_or(":".to_string()) },
+			(_, separator) => EnvOp::Prepend { name, value, separator: separator.unwrap_or(":".to_string()) }
+		};
+		Ok(EnvEntry { ops: vec![op] })
+	}
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct Step {
 	#[serde(default)]
-	pub args: Vec<Vec<String>>,
+	pub args: Vec<Command>,
+	// applied in order after the env of the config and the template
 	#[serde(default)]
-	pub env: Vec<HashMap<String, String>>
+	pub env: Vec<EnvEntry>
+}
+
+pub const BUILTIN_STEPS: [&str; 4] = ["prepare", "configure", "build", "install"];
+
+fn default_after() -> String {
+	"install".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NamedStep {
+	pub name: String,
+	// a built-in step or another named step
+	#[serde(default = "default_after")]
+	pub after: String,
+	#[serde(flatten)]
+	pub step: Step
 }
 
 #[derive(Deserialize, Debug)]
@@ -48,5 +204,284 @@ pub struct Recipe {
 	#[serde(default)]
 	pub build: Step,
 	#[serde(default)]
-	pub install: Step
+	pub install: Step,
+	// the test suite, only run by the test op
+	#[serde(default)]
+	pub check: Step,
+
+	#[serde(default)]
+	pub pre_prepare: Step,
+	#[serde(default)]
+	pub post_prepare: Step,
+	#[serde(default)]
+	pub pre_configure: Step,
+	#[serde(default)]
+	pub post_configure: Step,
+	#[serde(default)]
+	pub pre_build: Step,
+	#[serde(default)]
+	pub post_build: Step,
+	#[serde(default)]
+	pub pre_install: Step,
+	#[serde(default)]
+	pub post_install: Step,
+
+	#[serde(default)]
+	pub steps: Vec<NamedStep>
+}
+
+impl Recipe {
+	// the step with its pre and post hooks
+	pub fn builtin(&self, name: &str) -> [&Step; 3] {
+		match name {
+			"prepare" => [&self.pre_prepare, &self.prepare, &self.post_prepare],
+			"configure" => [&self.pre_configure, &self.configure, &self.post_configure],
+			"build" => [&self.pre_build, &self.build, &self.post_build],
+			"install" => [&self.pre_install, &self.install, &self.post_install],
+			_ => panic!("internal error: {} is not a built-in step, this is a qpkg bug!", name)
+		}
+	}
+
+	// looks up built-in steps, hooks like pre_build and named steps
+	pub fn step(&self, name: &str) -> Option<&Step> {
+		if let Some(builtin) = BUILTIN_STEPS.iter().find(|builtin| **builtin == name) {
+			return Some(self.builtin(builtin)[1]);
+		}
+		if name == "check" {
+			return Some(&self.check);
+		}
+		for builtin in BUILTIN_STEPS {
+			let [pre, _, post] = self.builtin(builtin);
+			if name.strip_prefix("pre_") == Some(builtin) {
+				return Some(pre);
+			}
+			if name.strip_prefix("post_") == Some(builtin) {
+				return Some(post);
+			}
+		}
+		self.steps.iter().find(|step| step.name == name).map(|step| &step.step)
+	}
+
+	pub fn steps_mut(&mut self) -> Vec<&mut Step> {
+		let mut steps = vec![
+			&mut self.prepare,
+			&mut self.configure,
+			&mut self.build,
+			&mut self.install,
+			&mut self.check,
+			&mut self.pre_prepare,
+			&mut self.post_prepare,
+			&mut self.pre_configure,
+			&mut self.post_configure,
+			&mut self.pre_build,
+			&mut self.post_build,
+			&mut self.pre_install,
+			&mut self.post_install
+		];
+		steps.extend(self.steps.iter_mut().map(|step| &mut step.step));
+		steps
+	}
+
+	// the named steps that run right after the given step, in the order they're declared
+	pub fn followers(&self, name: &str) -> Vec<&NamedStep> {
+		self.steps.iter().filter(|step| step.after == name).collect()
+	}
+
+	// the named steps that run after the built-in step, directly or through other named steps
+	pub fn steps_after(&self, builtin: &str) -> Vec<&NamedStep> {
+		let mut order = Vec::new();
+		let mut queue = self.followers(builtin);
+		queue.reverse();
+		while let Some(step) = queue.pop() {
+			order.push(step);
+			queue.extend(self.followers(&step.name).into_iter().rev());
+		}
+		order
+	}
+
+	// every step that runs after prepare in order, with the hooks around the built-in steps
+	pub fn build_order(&self) -> Vec<(String, &Step)> {
+		let mut order: Vec<_> = self.steps_after("prepare").into_iter()
+			.map(|step| (step.name.clone(), &step.step))
+			.collect();
+		for builtin in &BUILTIN_STEPS[1..] {
+			let [pre, step, post] = self.builtin(builtin);
+			order.push((format!("pre_{}", builtin), pre));
+			order.push((builtin.to_string(), step));
+			order.push((format!("post_{}", builtin), post));
+			order.extend(self.steps_after(builtin).into_iter().map(|step| (step.name.clone(), &step.step)));
+		}
+		order
+	}
+
+	// the built-in step a named step hangs off and the named steps leading to it, itself included
+	pub fn chain<'a>(&'a self, name: &'a str) -> (&'a str, Vec<&'a NamedStep>) {
+		let mut chain = Vec::new();
+		let mut name = name;
+		while let Some(step) = self.steps.iter().find(|step| step.name == name) {
+			chain.insert(0, step);
+			name = &step.after;
+		}
+		(name, chain)
+	}
+
+	// prepare, its hooks and the named steps after it run in the source with network access
+	pub fn in_prepare(&self, name: &str) -> bool {
+		matches!(self.chain(name).0, "prepare" | "pre_prepare" | "post_prepare")
+	}
+
+	pub fn check_steps(&self) -> Result<(), String> {
+		for step in &self.steps {
+			if step.name.is_empty() || step.name.contains(|c: char| c.is_whitespace() || c == '/') {
+				return Err(format!("the step name \"{}\" should not be empty or contain spaces or slashes", step.name));
+			}
+			if self.steps.iter().filter(|other| other.name == step.name).count() > 1 {
+				return Err(format!("the step {} is defined more than once", step.name));
+			}
+			// the lookup finds the built-in steps and hooks before the named steps
+			if self.step(&step.name).is_some_and(|found| !std::ptr::eq(found, &step.step)) {
+				return Err(format!("the step {} has the name of a built-in step or hook", step.name));
+			}
+			if !BUILTIN_STEPS.contains(&step.after.as_str()) && !self.steps.iter().any(|other| other.name == step.after) {
+				return Err(format!("the step {} runs after the undefined step {}", step.name, step.after));
+			}
+		}
+
+		// steps that never come after a built-in one wait on each other
+		let reachable: usize = BUILTIN_STEPS.iter().map(|builtin| self.steps_after(builtin).len()).sum();
+		if reachable != self.steps.len() {
+			return Err("the steps run after each other in a cycle".to_string());
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn recipe(steps: &str) -> Recipe {
+		let text = format!("[general]\nname = \"test\"\nversion = \"1\"\nsrc = []\nworkdir = \"\"\n{}", steps);
+		toml::from_str(&text).unwrap()
+	}
+
+	fn names(order: &[(String, &Step)]) -> Vec<String> {
+		order.iter().map(|(name, _)| name.clone()).collect()
+	}
+
+	#[test]
+	fn check_steps_accepts_valid_steps() {
+		let recipe = recipe(r#"
+			[[steps]]
+			name = "strip"
+			after = "fixup"
+			[[steps]]
+			name = "fixup"
+		"#);
+		assert_eq!(recipe.check_steps(), Ok(()));
+	}
+
+	#[test]
+	fn check_steps_rejects_invalid_steps() {
+		let cases = [
+			("name = \"two words\"", "should not be empty or contain spaces"),
+			("name = \"gen\"\n[[steps]]\nname = \"gen\"", "defined more than once"),
+			("name = \"build\"", "name of a built-in step or hook"),
+			("name = \"pre_install\"", "name of a built-in step or hook"),
+			("name = \"gen\"\nafter = \"nothing\"", "undefined step nothing"),
+			("name = \"a\"\nafter = \"b\"\n[[steps]]\nname = \"b\"\nafter = \"a\"", "cycle")
+		];
+		for (steps, error) in cases {
+			let recipe = recipe(&format!("[[steps]]\n{}", steps));
+			let result = recipe.check_steps();
+			assert!(result.as_ref().is_err_and(|e| e.contains(error)), "{}: {:?}", steps, result);
+		}
+	}
+
+	#[test]
+	fn build_order_puts_named_steps_after_their_step() {
+		let recipe = recipe(r#"
+			[[steps]]
+			name = "gen"
+			after = "prepare"
+			[[steps]]
+			name = "strip"
+			after = "fixup"
+			[[steps]]
+			name = "fixup"
+			[[steps]]
+			name = "docs"
+			after = "build"
+			[[steps]]
+			name = "late"
+		"#);
+		assert_eq!(names(&recipe.build_order()), [
+			"gen",
+			"pre_configure", "configure", "post_configure",
+			"pre_build", "build", "post_build", "docs",
+			"pre_install", "install", "post_install", "fixup", "strip", "late"
+		]);
+	}
+
+	#[test]
+	fn chain_leads_from_the_builtin_step() {
+		let recipe = recipe(r#"
+			[[steps]]
+			name = "fixup"
+			[[steps]]
+			name = "strip"
+			after = "fixup"
+			[[steps]]
+			name = "other"
+		"#);
+
+		let (builtin, chain) = recipe.chain("strip");
+		assert_eq!(builtin, "install");
+		assert_eq!(chain.iter().map(|step| step.name.as_str()).collect::<Vec<_>>(), ["fixup", "strip"]);
+
+		let (builtin, chain) = recipe.chain("build");
+		assert_eq!(builtin, "build");
+		assert!(chain.is_empty());
+		assert_eq!(recipe.chain("missing").0, "missing");
+	}
+
+	fn env_entry(pairs: &[(&str, &str)]) -> Result<EnvEntry, String> {
+		EnvEntry::try_from(pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<HashMap<_, _>>())
+	}
+
+	#[test]
+	fn env_entry_sets_the_variables_of_a_plain_table() {
+		let entry = env_entry(&[("FOO", "1"), ("BAR", "2")]).unwrap();
+		assert_eq!(entry.ops, [
+			EnvOp::Set { name: "BAR".to_string(), value: "2".to_string() },
+			EnvOp::Set { name: "FOO".to_string(), value: "1".to_string() }
+		]);
+	}
+
+	#[test]
+	fn env_entry_rejects_mixed_keys() {
+		let result = env_entry(&[("set", "FOO"), ("append", "BAR"), ("value", "1")]);
+		assert!(result.as_ref().is_err_and(|e| e.contains("only have one of")), "{:?}", result);
+
+		let result = env_entry(&[("set", "FOO"), ("value", "1"), ("BAR", "2")]);
+		assert!(result.as_ref().is_err_and(|e| e.contains("unknown key BAR")), "{:?}", result);
+	}
+
+	#[test]
+	fn env_entry_rejects_unset_with_a_value() {
+		let result = env_entry(&[("unset", "FOO"), ("value", "1")]);
+		assert!(result.as_ref().is_err_and(|e| e.contains("takes no value")), "{:?}", result);
+
+		let entry = env_entry(&[("unset", "FOO")]).unwrap();
+		assert_eq!(entry.ops, [EnvOp::Unset { name: "FOO".to_string() }]);
+	}
+
+	#[test]
+	fn env_entry_defaults_to_a_colon_separator() {
+		let entry = env_entry(&[("append", "PATH"), ("value", "/opt/bin")]).unwrap();
+		assert_eq!(entry.ops, [EnvOp::Append { name: "PATH".to_string(), value: "/opt/bin".to_string(), separator: ":".to_string() }]);
+
+		let entry = env_entry(&[("prepend", "FLAGS"), ("value", "-O2"), ("separator", " ")]).unwrap();
+		assert_eq!(entry.ops, [EnvOp::Prepend { name: "FLAGS".to_string(), value: "-O2".to_string(), separator: " ".to_string() }]);
+	}
 }
@@ -0,0 +1,222 @@
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::process::{exit, Command};
+use toml_edit::{DocumentMut, Item, Table, value};
+use crate::build::Recipe;
+use crate::output::Event;
+use crate::{create_dir, display_name, fetch_command, is_tarball, load_finalized_recipe, logs, output, package_dirs, patches, recipes_dir, remove_file, reproducible, source_path, State};
+
+fn write_recipe(path: &Path, doc: &DocumentMut) {
+	if let Err(e) = write(path, doc.to_string()) {
+		output::error(format_args!("failed to write {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+fn run(cmd: &mut Command, program: &str) -> bool {
+	match cmd.output() {
+		Ok(output) => output.status.success(),
+		Err(e) => {
+			output::error(format_args!("failed to spawn {}: {}", program, e));
+			exit(1);
+		}
+	}
+}
+
+// prints an error and returns false if a source couldn't be fetched
+pub fn fetch_sources(recipe: &Recipe, archives_dir: &Path, log_path: &Path) -> bool {
+	for src in &recipe.general.src {
+		let path = source_path(recipe, archives_dir, src);
+		if path.exists() {
+			continue;
+		}
+
+		let Some((mut cmd, method, url)) = fetch_command(src, &path, recipe.general.recurse_submodules) else {
+			continue;
+		};
+
+		output::info(format_args!("fetching {} using {}", url, method));
+		let status = logs::run(&mut cmd, &logs::open_log(log_path));
+		if let Some(message) = logs::failure(&status, method) {
+			output::error(format_args!("{}, see {}", message, log_path.display()));
+			if method == "wget" {
+				remove_file(&path);
+			}
+			return false;
+		}
+	}
+	true
+}
+
+// unpacks the archives and copies the git clones into dir like prepare does
+pub fn unpack(recipe: &Recipe, archives_dir: &Path, dir: &Path) {
+	if recipe.general.no_auto_unpack {
+		return;
+	}
+
+	for src in &recipe.general.src {
+		let path = source_path(recipe, archives_dir, src);
+		if !path.exists() {
+			continue;
+		}
+
+		if is_tarball(src) {
+			if !run(Command::new("tar").arg("-xf").arg(&path).current_dir(dir), "tar") {
+				output::error(format_args!("failed to unpack {}", path.display()));
+				exit(1);
+			}
+		} else if src.contains(".git") && !run(Command::new("cp").arg("-a").arg(&path).arg(dir.join(&recipe.general.workdir)), "cp") {
+			output::error(format_args!("failed to copy {}", path.display()));
+			exit(1);
+		}
+	}
+}
+
+pub fn bump(state: &State, name: &str, version: &str, host: bool) {
+	let recipe_path = recipes_dir(state, host).join(name).join("build.toml");
+	let text = match read_to_string(&recipe_path) {
+		Ok(text) => text,
+		Err(e) => {
+			output::error(format_args!("failed to read {}: {}", recipe_path.display(), e));
+			exit(1);
+		}
+	};
+	let mut doc = match text.parse::<DocumentMut>() {
+		Ok(doc) => doc,
+		Err(e) => {
+			output::error(format_args!("failed to parse {}: {}", recipe_path.display(), e));
+			exit(1);
+		}
+	};
+
+	let Some(general) = doc.get_mut("general").and_then(|general| general.as_table_like_mut()) else {
+		output::error(format_args!("{} has no general section", recipe_path.display()));
+		exit(1);
+	};
+	let old_version = general.get("version")
+		.and_then(|version| version.as_str())
+		.unwrap_or_default()
+		.to_string();
+	if old_version == version {
+		output::error(format_args!("{} is already at version {}", display_name(name, host), version));
+		exit(1);
+	}
+
+	// keeps the comments and whitespace around the old value
+	match general.get_mut("version").and_then(|item| item.as_value_mut()) {
+		Some(old) => {
+			let decor = old.decor().clone();
+			*old = version.into();
+			*old.decor_mut() = decor;
+		},
+		None => {
+			general.insert("version", value(version));
+		}
+	}
+	write_recipe(&recipe_path, &doc);
+
+	output::info(format_args!("bumped {} from {} to {}", display_name(name, host), old_version, version));
+
+	let build_root = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
+	let archives_dir = build_root.join("archives");
+	create_dir(&archives_dir);
+
+	let recipe = load_finalized_recipe(state, name, host);
+	let log_path = logs::log_path(&build_root, name, host, "fetch");
+
+	if !fetch_sources(&recipe, &archives_dir, &log_path) {
+		// the old version still builds so it's kept until the new source is available
+		if let Err(e) = write(&recipe_path, &text) {
+			output::error(format_args!("failed to restore {}: {}", recipe_path.display(), e));
+		}
+		exit(1);
+	}
+
+	let checksums: Vec<_> = recipe.general.src.iter()
+		.map(|src| source_path(&recipe, &archives_dir, src))
+		.filter(|path| recipe.general.src_unpack_dir.is_empty() && path.is_file())
+		.map(|path| (path.file_name().unwrap().to_str().unwrap().to_string(), reproducible::hash_file(&path)))
+		.collect();
+
+	// the checksums of the old archives are dropped
+	if !checksums.is_empty() {
+		// an existing table is refilled in place to keep it inline or standard as it was
+		match doc["general"].get_mut("checksums").and_then(Item::as_table_like_mut) {
+			Some(table) => {
+				table.clear();
+				for (file_name, checksum) in &checksums {
+					table.insert(file_name, value(checksum));
+				}
+			},
+			None => {
+				let mut table = Table::new();
+				for (file_name, checksum) in &checksums {
+					table.insert(file_name, value(checksum));
+				}
+				doc["general"]["checksums"] = Item::Table(table);
+			}
+		}
+		write_recipe(&recipe_path, &doc);
+		output::info(format_args!("updated the checksums of {}", display_name(name, host)));
+	}
+
+	let target = if host { "host" } else { &state.config.general.target };
+	let patches = patches::series(&recipe, &patches::dir(recipes_dir(state, host), name), target);
+
+	// the patches are tried against a scratch copy so that the real source dir stays untouched
+	let scratch_dir = build_root.join("bump").join(name);
+	if scratch_dir.exists() && let Err(e) = std::fs::remove_dir_all(&scratch_dir) {
+		output::error(format_args!("failed to remove {}: {}", scratch_dir.display(), e));
+		exit(1);
+	}
+	create_dir(&scratch_dir);
+	let work_dir = scratch_dir.join(&recipe.general.workdir);
+
+	if !patches.is_empty() {
+		unpack(&recipe, &archives_dir, &scratch_dir);
+		output::info("patches:");
+	}
+
+	let patch_log = logs::open_log(&logs::log_path(&build_root, name, host, "bump"));
+
+	let mut failed = 0;
+	for patch in &patches {
+		let dry_run = patches::apply(patch, &work_dir, &patch_log, true);
+
+		let status = if !dry_run.status.success() {
+			failed += 1;
+			"fail"
+		} else if dry_run.warnings.iter().any(|warning| warning.contains("with fuzz")) {
+			"fuzz"
+		} else if !dry_run.warnings.is_empty() {
+			"offset"
+		} else {
+			"clean"
+		};
+		output::emit(Event::PatchChecked { package: name, patch: patch.name(), status });
+
+		// later patches may depend on the earlier ones
+		if dry_run.status.success() {
+			patches::apply(patch, &work_dir, &patch_log, false);
+		}
+	}
+
+	if let Err(e) = std::fs::remove_dir_all(&scratch_dir) {
+		output::error(format_args!("failed to remove {}: {}", scratch_dir.display(), e));
+		exit(1);
+	}
+
+	let [root_src_dir, build_dir, _] = package_dirs(&build_root, name, host);
+	remove_file(root_src_dir.join("qpkg.prepared"));
+	for stamp in ["qpkg.configured", "qpkg.built", "qpkg.installed"] {
+		remove_file(build_dir.join(stamp));
+	}
+
+	if failed != 0 {
+		output::emit(Event::Warning {
+			package: Some(name),
+			message: format!("{} patches failed to apply, refresh them before building", failed)
+		});
+	}
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs::read_dir;
+use std::path::Path;
+use std::process::exit;
+use walkdir::WalkDir;
+use crate::{archive_name, display_name, installed, load_finalized_recipe, output, package_dirs, recipes_dir, remove_file, source_path, State};
+
+pub struct CleanOptions {
+	pub sources: bool,
+	pub builds: bool,
+	pub dest: bool,
+	pub archives: bool
+}
+
+fn disk_usage(path: &Path) -> u64 {
+	WalkDir::new(path)
+		.into_iter()
+		.flatten()
+		.filter_map(|file| file.path().symlink_metadata().ok())
+		.filter(|meta| !meta.is_dir())
+		.map(|meta| meta.len())
+		.sum()
+}
+
+fn format_size(bytes: u64) -> String {
+	let mut size = bytes as f64;
+	for unit in ["B", "KiB", "MiB", "GiB"] {
+		if size < 1024.0 {
+			return format!("{:.1} {}", size, unit);
+		}
+		size /= 1024.0;
+	}
+	format!("{:.1} TiB", size)
+}
+
+// returns the amount of bytes freed
+fn remove(path: &Path) -> u64 {
+	let Ok(meta) = path.symlink_metadata() else {
+		return 0;
+	};
+
+	let size = disk_usage(path);
+	output::info(format_args!("removing {}", path.display()));
+
+	if meta.is_dir() {
+		if let Err(e) = std::fs::remove_dir_all(path) {
+			output::error(format_args!("failed to remove {}: {}", path.display(), e));
+			exit(1);
+		}
+	} else {
+		remove_file(path);
+	}
+
+	size
+}
+
+pub fn clean(state: &State, names: &[String], host: bool, options: &CleanOptions) {
+	let build_root = Path::new(&state.config.general.build_root);
+	let mut freed = 0;
+
+	for name in names {
+		if !recipes_dir(state, host).join(name).join("build.toml").exists() {
+			output::error(format_args!("no recipe for {}", display_name(name, host)));
+			exit(1);
+		}
+
+		let [root_src_dir, build_dir, dest_dir] = package_dirs(build_root, name, host);
+
+		if options.sources {
+			freed += remove(&root_src_dir);
+		}
+		if options.builds {
+			freed += remove(&build_dir);
+		}
+		if options.dest {
+			freed += remove(&dest_dir);
+			// otherwise the next run would sync an empty dest dir instead of installing again
+			remove_file(build_dir.join("qpkg.installed"));
+
+			let host_meta_dir = build_root.join("host_meta");
+			if host && let Some(host_prefix) = &state.config.general.host_prefix && host_meta_dir.join(name).exists() {
+				installed::uninstall(&host_meta_dir, Path::new(host_prefix), name);
+			}
+		}
+		if options.archives {
+			let recipe = load_finalized_recipe(state, name, host);
+			if recipe.general.src_unpack_dir.is_empty() {
+				for src in &recipe.general.src {
+					freed += remove(&source_path(&recipe, &build_root.join("archives"), src));
+				}
+			}
+		}
+	}
+
+	output::info(format_args!("freed {}", format_size(freed)));
+}
+
+fn recipe_names(dir: &Path) -> HashSet<String> {
+	let Ok(dir) = read_dir(dir) else {
+		return HashSet::new();
+	};
+
+	dir.flatten()
+		.filter(|entry| entry.path().join("build.toml").exists())
+		.filter_map(|entry| entry.file_name().into_string().ok())
+		.collect()
+}
+
+pub fn gc(state: &State) {
+	let build_root = Path::new(&state.config.general.build_root);
+	let mut freed = 0;
+
+	let mut archives = HashSet::new();
+
+	for host in [false, true] {
+		let names = recipe_names(recipes_dir(state, host));
+
+		for name in &names {
+			let recipe = load_finalized_recipe(state, name, host);
+			if recipe.general.src_unpack_dir.is_empty() {
+				archives.extend(recipe.general.src.iter().map(|src| archive_name(src)));
+			}
+		}
+
+		let dirs = if host {
+			["host_sources", "host_builds", "host_pkgs", "host_meta", "host_logs", "host_timings"].as_slice()
+		} else {
+			["sources", "pkg_builds", "pkgs", "logs", "timings", "repro"].as_slice()
+		};
+
+		for dir in dirs {
+			let Ok(entries) = read_dir(build_root.join(dir)) else {
+				continue;
+			};
+
+			for entry in entries.flatten() {
+				let path = entry.path();
+				let name = if *dir == "timings" || *dir == "host_timings" {
+					path.file_stem()
+				} else {
+					path.file_name()
+				};
+
+				let Some(name) = name.and_then(|name| name.to_str()) else {
+					continue;
+				};
+				if names.contains(name) {
+					continue;
+				}
+
+				// the files of removed host recipes are also dropped from the host prefix
+				if *dir == "host_meta" && let Some(host_prefix) = &state.config.general.host_prefix {
+					output::info(format_args!("removing {} from {}", display_name(name, true), host_prefix));
+					installed::uninstall(&build_root.join(dir), Path::new(host_prefix), name);
+					continue;
+				}
+
+				freed += remove(&path);
+			}
+		}
+	}
+
+	if let Ok(entries) = read_dir(build_root.join("archives")) {
+		for entry in entries.flatten() {
+			if entry.file_name().to_str().is_none_or(|name| !archives.contains(name)) {
+				freed += remove(&entry.path());
+			}
+		}
+	}
+
+	output::info(format_args!("freed {}", format_size(freed)));
+}
@@ -0,0 +1,308 @@
+use std::fs::read_dir;
+use std::path::Path;
+use std::process::exit;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCandidates, CompletionCandidate};
+use clap_complete::env::{Bash, EnvCompleter, Fish, Zsh};
+use crate::try_load_config;
+use crate::output::{self, Format};
+
+#[derive(Parser)]
+#[command(
+	name = "qpkg",
+	version,
+	about = "Build packages from recipes and sync them into a sysroot",
+	arg_required_else_help = true)]
+pub struct Cli {
+	#[arg(long, global = true, value_name = "PATH", help = "Path to qpkg.toml, defaults to ./qpkg.toml or /etc/qpkg.toml")]
+	pub config: Option<String>,
+	#[arg(long, global = true, value_enum, default_value_t = Format::Text, help = "Print one json event per line instead of info messages")]
+	pub output: Format,
+	#[arg(long, global = true, help = "Operate on host packages")]
+	pub host: bool,
+	#[arg(long, global = true, value_name = "NAME=VALUE", value_parser = parse_env, help = "Set an environment variable for the recipe commands")]
+	pub env: Vec<(String, String)>,
+	#[command(subcommand)]
+	pub command: Command
+}
+
+#[derive(Args, Default, Clone, Copy)]
+pub struct RunArgs {
+	#[arg(long, help = "Initialize a git repository during prepare if one doesn't exist and commit each patch")]
+	pub dev: bool,
+	#[arg(long, help = "Run recipe commands in a bubblewrap sandbox")]
+	pub sandbox: bool,
+	#[arg(long, help = "Start $SHELL in the working directory of a failed command")]
+	pub shell_on_failure: bool,
+	#[arg(long, help = "Skip failed packages and their dependents instead of stopping")]
+	pub keep_going: bool
+}
+
+#[derive(Args)]
+pub struct Packages {
+	#[arg(required = true, value_name = "PACKAGE", help = "Names of the recipes", add = ArgValueCandidates::new(recipe_candidates))]
+	pub names: Vec<String>
+}
+
+#[derive(Args)]
+pub struct StepArgs {
+	#[arg(long, help = "Rerun the step even if it already ran")]
+	pub force: bool,
+	#[arg(long, help = "Also sync the packages into the sysroot")]
+	pub sync: bool,
+	#[arg(long, help = "Print what would be fetched, built and synced without doing it")]
+	pub dry_run: bool,
+	#[command(flatten)]
+	pub run: RunArgs,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct RunStepArgs {
+	#[arg(help = "A built-in step or a named step of the recipes, hooks like pre_build run with their step")]
+	pub step: String,
+	#[command(flatten)]
+	pub args: StepArgs
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+	#[arg(long, help = "Print what would be fetched, built and synced without doing it")]
+	pub dry_run: bool,
+	#[command(flatten)]
+	pub run: RunArgs,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct TestArgs {
+	#[arg(long, help = "Print what would be fetched, built and tested without doing it")]
+	pub dry_run: bool,
+	#[command(flatten)]
+	pub run: RunArgs,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct EnvArgs {
+	#[arg(long, default_value = "build", help = "The step whose environment is used, a built-in step, a hook like pre_build or a named step")]
+	pub step: String,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct ShellArgs {
+	#[arg(long, help = "Start the shell in a bubblewrap sandbox")]
+	pub sandbox: bool,
+	#[command(flatten)]
+	pub env: EnvArgs
+}
+
+#[derive(Args)]
+pub struct ReproArgs {
+	#[arg(long, help = "Use a different build path for the second build")]
+	pub vary_path: bool,
+	#[arg(long, help = "Use a different umask for the second build")]
+	pub vary_umask: bool,
+	#[arg(long, help = "Run the second build a year in the future using faketime")]
+	pub vary_time: bool,
+	#[command(flatten)]
+	pub run: RunArgs,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct GenPatchArgs {
+	#[arg(
+		long,
+		value_name = "PACKAGE",
+		num_args = 1..,
+		conflicts_with_all = ["name", "pattern", "names"],
+		help = "Regenerate patches/ and its series from the commits on top of the base of each package prepared with --dev",
+		add = ArgValueCandidates::new(recipe_candidates))]
+	pub series: Vec<String>,
+	#[arg(value_parser = parse_patch_name, required_unless_present = "series", help = "The patch is written to patches/<NAME>.patch")]
+	pub name: Option<String>,
+	#[arg(required_unless_present = "series", help = "The pathspec passed to git diff")]
+	pub pattern: Option<String>,
+	#[arg(value_name = "PACKAGE", required_unless_present = "series", help = "Names of the recipes", add = ArgValueCandidates::new(recipe_candidates))]
+	pub names: Vec<String>
+}
+
+#[derive(Args)]
+pub struct RemoveArgs {
+	#[arg(long, help = "Also remove the installed packages that depend on the packages")]
+	pub cascade: bool,
+	#[arg(long, help = "Remove the packages even if installed packages depend on them")]
+	pub force: bool,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct NewArgs {
+	#[arg(help = "Name of the new recipe")]
+	pub name: String,
+	#[arg(help = "An http(s) or git url of the source")]
+	pub url: String,
+	#[arg(long, help = "The template to use instead of one picked based on the build system")]
+	pub template: Option<String>
+}
+
+#[derive(Args)]
+pub struct WatchArgs {
+	#[arg(long, help = "Also rebuild the packages that depend on a changed package and were built before")]
+	pub reverse_deps: bool,
+	#[arg(long, value_name = "MS", default_value_t = 500, help = "How long it has to be quiet after a change before rebuilding")]
+	pub debounce: u64,
+	#[command(flatten)]
+	pub run: RunArgs,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Args)]
+pub struct BumpArgs {
+	#[arg(help = "Name of the recipe", add = ArgValueCandidates::new(recipe_candidates))]
+	pub name: String,
+	#[arg(help = "The new version")]
+	pub version: String
+}
+
+#[derive(Args)]
+pub struct PatchRefreshArgs {
+	#[arg(long, value_name = "VERSION", required_unless_present_any = ["resume", "abort"], help = "The version the patches apply to")]
+	pub from: Option<String>,
+	#[arg(long = "continue", conflicts_with_all = ["from", "abort"], help = "Continue after the conflicts were resolved and write the patches")]
+	pub resume: bool,
+	#[arg(long, conflicts_with = "from", help = "Drop the refresh in progress")]
+	pub abort: bool,
+	#[arg(help = "Name of the recipe", add = ArgValueCandidates::new(recipe_candidates))]
+	pub name: String
+}
+
+#[derive(Args)]
+pub struct CleanArgs {
+	#[arg(long, help = "Remove the unpacked sources")]
+	pub sources: bool,
+	#[arg(long, help = "Remove the build dirs")]
+	pub builds: bool,
+	#[arg(long, help = "Remove the dest dirs")]
+	pub dest: bool,
+	#[arg(long, help = "Remove the downloaded archives and git clones")]
+	pub archives: bool,
+	#[command(flatten)]
+	pub packages: Packages
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum Shell {
+	Bash,
+	Zsh,
+	Fish
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+	#[command(about = "Fetch, unpack and patch the sources and run the prepare step")]
+	Prepare(StepArgs),
+	#[command(about = "Prepare and run the configure step")]
+	Configure(StepArgs),
+	#[command(about = "Configure and run the build step")]
+	Build(StepArgs),
+	#[command(about = "Build and run the install step into the dest dir")]
+	Install(StepArgs),
+	#[command(about = "Run a step and the steps it comes after, --force only reruns the step itself")]
+	Step(RunStepArgs),
+	#[command(about = "Build the packages and run their check step without installing them")]
+	Test(TestArgs),
+	#[command(about = "Copy the dest dirs into the sysroot")]
+	Sync(SyncArgs),
+	#[command(about = "Force the build and install steps and sync the packages")]
+	Rebuild(SyncArgs),
+	#[command(about = "Rebuild, install and sync the packages whenever their sources or recipes change")]
+	Watch(WatchArgs),
+	#[command(about = "Remove the files of the packages from the sysroot")]
+	Remove(RemoveArgs),
+	#[command(about = "Remove the packages installed as dependencies that nothing needs anymore")]
+	Autoremove,
+	#[command(about = "Fetch a source and write a recipe for it based on its contents")]
+	New(NewArgs),
+	#[command(about = "Set a new version in a recipe, update its checksums and check which patches still apply")]
+	Bump(BumpArgs),
+	#[command(about = "Rebase the patches from an older version onto the current one and write them back")]
+	PatchRefresh(PatchRefreshArgs),
+	#[command(about = "Remove build artefacts of the packages, by default the sources, builds and dest dirs")]
+	Clean(CleanArgs),
+	#[command(about = "Remove everything in the build root that no current recipe refers to")]
+	Gc,
+	#[command(about = "Write the git diff of the working directory to a patch in the recipe")]
+	GenPatch(GenPatchArgs),
+	#[command(about = "Print the slowest packages and the critical path, and write trace.json")]
+	Report,
+	#[command(about = "Print the environment the commands of a step are run with")]
+	Env(EnvArgs),
+	#[command(about = "Start $SHELL in the working directory of a step with its environment")]
+	Shell(ShellArgs),
+	#[command(about = "Build the packages twice in fresh build dirs and compare the results")]
+	CheckRepro(ReproArgs),
+	#[command(about = "Print the completion script for a shell")]
+	Completions {
+		shell: Shell
+	}
+}
+
+fn parse_env(arg: &str) -> Result<(String, String), String> {
+	match arg.split_once('=') {
+		Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+		_ => Err(format!("expected NAME=VALUE, got {}", arg))
+	}
+}
+
+fn parse_patch_name(arg: &str) -> Result<String, String> {
+	if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('/') {
+		return Err("the patch name should not be empty or contain spaces or slashes".to_string());
+	}
+	Ok(arg.to_string())
+}
+
+// --config and --host aren't known while completing so the default config and both recipe dirs are used
+fn recipe_candidates() -> Vec<CompletionCandidate> {
+	let Some(config) = ["qpkg.toml", "/etc/qpkg.toml"].into_iter()
+		.find(|path| Path::new(path).exists())
+		.and_then(try_load_config) else {
+		return Vec::new();
+	};
+
+	let mut names: Vec<_> = [&config.general.recipes_dir, &config.general.host_recipes_dir]
+		.into_iter()
+		.filter_map(|dir| read_dir(dir).ok())
+		.flat_map(|dir| dir.flatten())
+		.filter(|entry| entry.path().join("build.toml").exists())
+		.filter_map(|entry| entry.file_name().into_string().ok())
+		.collect();
+	names.sort();
+	names.dedup();
+
+	names.into_iter().map(CompletionCandidate::new).collect()
+}
+
+// the script calls back into this binary with COMPLETE set, which is handled at the start of main
+pub fn print_completions(shell: Shell) {
+	let completer: &dyn EnvCompleter = match shell {
+		Shell::Bash => &Bash,
+		Shell::Zsh => &Zsh,
+		Shell::Fish => &Fish
+	};
+
+	let exe = std::env::current_exe().expect("failed to get the path of qpkg");
+	if let Err(e) = completer.write_registration("COMPLETE", "qpkg", "qpkg", exe.to_str().unwrap(), &mut std::io::stdout()) {
+		output::error(format_args!("failed to write completions: {}", e));
+		exit(1);
+	}
+}
@@ -0,0 +1,208 @@
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::process::{exit, Command, Output};
+use crate::patches::Patch;
+use crate::{create_dir, output, remove_file};
+
+// marks the unpacked source that the patch commits are on top of
+pub const BASE_TAG: &str = "qpkg-base";
+
+pub fn git(work_dir: &Path, args: &[&str]) -> Output {
+	let output = match Command::new("git").args(args).current_dir(work_dir).output() {
+		Ok(output) => output,
+		Err(e) => {
+			output::error(format_args!("failed to spawn git: {}", e));
+			exit(1);
+		}
+	};
+
+	if !output.status.success() {
+		output::error(format_args!(
+			"git {} failed with {}: {}",
+			args[0],
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()));
+		exit(1);
+	}
+	output
+}
+
+// runs git mailinfo on the patch, which writes its message and diff to msg_path and patch_path
+// and prints the fields of its header
+fn mailinfo(work_dir: &Path, patch: &Patch, msg_path: &Path, patch_path: &Path) -> String {
+	let output = match Command::new("git")
+		.arg("mailinfo")
+		.arg(msg_path)
+		.arg(patch_path)
+		.stdin(std::fs::File::open(&patch.path).expect("failed to open patch"))
+		.current_dir(work_dir)
+		.output() {
+		Ok(output) => output,
+		Err(e) => {
+			output::error(format_args!("failed to spawn git: {}", e));
+			exit(1);
+		}
+	};
+	String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn field<'a>(info: &'a str, name: &str) -> Option<&'a str> {
+	info.lines()
+		.find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+		.filter(|value| !value.is_empty())
+}
+
+fn subject<'a>(info: &'a str, patch: &'a Patch) -> &'a str {
+	field(info, "Subject").unwrap_or_else(|| patch.path.file_stem().unwrap().to_str().unwrap())
+}
+
+// commits the applied patch using the author, date and message of its header if it has one
+pub fn commit_patch(work_dir: &Path, patch: &Patch) {
+	let git_dir = work_dir.join(".git");
+	let msg_path = git_dir.join("qpkg-msg");
+	let patch_path = git_dir.join("qpkg-patch");
+
+	let info = mailinfo(work_dir, patch, &msg_path, &patch_path);
+
+	let body = read_to_string(&msg_path).unwrap_or_default();
+	let msg = format!("{}\n\n{}", subject(&info, patch), body);
+	if let Err(e) = write(&msg_path, msg) {
+		output::error(format_args!("failed to write {}: {}", msg_path.display(), e));
+		exit(1);
+	}
+
+	git(work_dir, &["add", "-A"]);
+
+	let author = field(&info, "Author")
+		.zip(field(&info, "Email"))
+		.map(|(name, email)| format!("{} <{}>", name, email));
+	let mut args = vec!["commit", "-q", "--allow-empty", "--cleanup=strip", "-F", msg_path.to_str().unwrap()];
+	if let Some(author) = &author {
+		args.extend(["--author", author]);
+	}
+	if let Some(date) = field(&info, "Date") {
+		args.extend(["--date", date]);
+	}
+	git(work_dir, &args);
+
+	remove_file(&msg_path);
+	remove_file(&patch_path);
+}
+
+// the subject commit_patch gave the commit of the patch
+fn commit_subject(work_dir: &Path, patch: &Patch) -> String {
+	let git_dir = work_dir.join(".git");
+	let msg_path = git_dir.join("qpkg-msg");
+	let patch_path = git_dir.join("qpkg-patch");
+
+	let info = mailinfo(work_dir, patch, &msg_path, &patch_path);
+	remove_file(&msg_path);
+	remove_file(&patch_path);
+	subject(&info, patch).to_string()
+}
+
+// format-patch arguments that give the exported patch the path prefixes of the original one so
+// that it applies with the same -p
+fn prefix_args(patch: &Patch) -> Vec<String> {
+	let strip = patch.strip as usize;
+	if strip == 0 {
+		return vec!["--no-prefix".to_string()];
+	}
+
+	let text = read_to_string(&patch.path).unwrap_or_default();
+	let prefix = |marker: &str, default: &str| text.lines()
+		.filter_map(|line| line.strip_prefix(marker))
+		.map(|path| path.split('\t').next().unwrap())
+		.find(|path| *path != "/dev/null")
+		.map(|path| path.split('/').collect::<Vec<_>>())
+		.filter(|parts| parts.len() > strip)
+		.map_or_else(|| default.repeat(strip), |parts| parts[..strip].join("/") + "/");
+
+	vec![
+		format!("--src-prefix={}", prefix("--- ", "a/")),
+		format!("--dst-prefix={}", prefix("+++ ", "b/"))
+	]
+}
+
+// replaces the patches that were applied with one patch per commit on top of the base and
+// rewrites the series, patches that don't apply to this version or target are kept as they are
+pub fn export_series(work_dir: &Path, dir: &Path, all: &[Patch], applied: &[Patch]) {
+	let has_base = Command::new("git")
+		.args(["rev-parse", "-q", "--verify", BASE_TAG])
+		.current_dir(work_dir)
+		.output()
+		.is_ok_and(|output| output.status.success());
+	if !has_base {
+		output::error(format_args!("{} has no {} tag, prepare it again with --dev", work_dir.display(), BASE_TAG));
+		exit(1);
+	}
+
+	create_dir(dir);
+
+	// read before the patches are removed, the commits are matched to the patches they were made
+	// from by subject or else by position so that the exported ones keep their options
+	let subjects: Vec<_> = applied.iter().map(|patch| commit_subject(work_dir, patch)).collect();
+	let prefixes: Vec<_> = applied.iter().map(prefix_args).collect();
+
+	for patch in applied {
+		remove_file(&patch.path);
+	}
+
+	let range = format!("{}..HEAD", BASE_TAG);
+	let log = git(work_dir, &["log", "--reverse", "--format=%H %s", &range]);
+	let log = String::from_utf8_lossy(&log.stdout);
+	let commits: Vec<_> = log.lines()
+		.filter_map(|line| line.split_once(' '))
+		.collect();
+
+	let mut matched = vec![false; applied.len()];
+	let mut series = Vec::new();
+	for (i, (hash, commit_subject)) in commits.iter().enumerate() {
+		let original = subjects.iter()
+			.enumerate()
+			.position(|(j, subject)| !matched[j] && subject == commit_subject)
+			.or_else(|| (commits.len() == applied.len() && !matched[i]).then_some(i));
+		if let Some(j) = original {
+			matched[j] = true;
+		}
+
+		let start = (i + 1).to_string();
+		let mut args = vec![
+			"format-patch",
+			"-1",
+			hash,
+			"--zero-commit",
+			"--no-signature",
+			"--no-numbered",
+			"--start-number",
+			&start,
+			"-o",
+			dir.to_str().unwrap()
+		];
+		if let Some(j) = original {
+			args.extend(prefixes[j].iter().map(String::as_str));
+		}
+		let output = git(work_dir, &args);
+
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let file = Path::new(stdout.trim()).file_name().unwrap().to_str().unwrap();
+		series.push(match original {
+			Some(j) => applied[j].renamed(dir, file).series_line(),
+			None => file.to_string()
+		});
+	}
+	let exported = series.len();
+
+	series.extend(all.iter()
+		.filter(|patch| !applied.iter().any(|other| other.path == patch.path))
+		.map(|patch| patch.series_line()));
+
+	let series_path = dir.join("series");
+	let series = series.iter().map(|line| format!("{}\n", line)).collect::<String>();
+	if let Err(e) = write(&series_path, series) {
+		output::error(format_args!("failed to write {}: {}", series_path.display(), e));
+		exit(1);
+	}
+
+	output::info(format_args!("wrote {} patches to {}", exported, dir.display()));
+}
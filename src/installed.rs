@@ -0,0 +1,272 @@
+use std::fs::{read_dir, read_to_string, write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::exit;
+use crate::{create_dir, dest_files, display_name, output, remove_file, remove_path, reproducible, State};
+
+const EXPLICIT: &str = "explicit";
+const DEPENDENCY: &str = "dependency";
+
+fn read_list(pkg_meta_dir: &Path, file: &str) -> Vec<String> {
+	read_to_string(pkg_meta_dir.join(file))
+		.unwrap_or_default()
+		.lines()
+		.map(|line| line.trim())
+		.filter(|line| !line.is_empty())
+		.map(String::from)
+		.collect()
+}
+
+fn write_file(path: &Path, data: String) {
+	if let Err(e) = write(path, data) {
+		output::error(format_args!("failed to write {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+// packages installed before reasons were recorded are treated as explicitly installed
+fn is_explicit(pkg_meta_dir: &Path) -> bool {
+	match read_to_string(pkg_meta_dir.join("REASON")) {
+		Ok(reason) => reason.trim() != DEPENDENCY,
+		Err(_) => true
+	}
+}
+
+// an explicitly installed package stays explicit when it's later synced as a dependency
+pub fn record(pkg_meta_dir: &Path, explicit: bool, depends: &[String]) {
+	create_dir(pkg_meta_dir);
+
+	let explicit = explicit || read_to_string(pkg_meta_dir.join("REASON"))
+		.is_ok_and(|reason| reason.trim() == EXPLICIT);
+	let reason = if explicit { EXPLICIT } else { DEPENDENCY };
+
+	write_file(&pkg_meta_dir.join("REASON"), format!("{}\n", reason));
+	write_file(
+		&pkg_meta_dir.join("DEPENDS"),
+		depends.iter().map(|dep| format!("{}\n", dep)).collect());
+}
+
+// copies the dest dir into root and removes the files of the previous sync that are gone,
+// returns the amount of added, removed and total files
+pub fn sync(
+	state: &State,
+	dest_dir: &Path,
+	root: &Path,
+	pkg_meta_dir: &Path,
+	epoch: Option<u64>
+) -> Result<(usize, usize, usize), String> {
+	if !dest_dir.exists() {
+		return Err(format!("dest dir {} doesn't exist", dest_dir.display()));
+	}
+
+	let abs_dest_dir = dest_dir.canonicalize().expect("failed to canonizalize dest dir");
+	let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
+
+	let mut files = String::new();
+
+	for path in dest_files(state, &abs_dest_dir) {
+		let src_path = abs_dest_dir.join(&path);
+		let file_type = src_path.symlink_metadata()
+			.expect("failed to query file metadata")
+			.file_type();
+		let full_path = root.join(&path);
+
+		if file_type.is_dir() {
+			create_dir(&full_path);
+		} else if file_type.is_symlink() {
+			let orig = std::fs::read_link(&src_path)
+				.expect("failed to resolve symlink");
+			match std::os::unix::fs::symlink(orig, &full_path) {
+				Ok(_) => {},
+				Err(e) => {
+					if e.kind() != std::io::ErrorKind::AlreadyExists {
+						return Err(format!("failed to create symlink {}: {}", full_path.display(), e));
+					}
+				}
+			}
+		} else {
+			if full_path.exists() {
+				let mut perms = full_path
+					.metadata()
+					.expect("failed to query file metadata")
+					.permissions();
+				// owner/group write
+				perms.set_mode(perms.mode() | 0o220);
+				std::fs::set_permissions(&full_path, perms)
+					.expect("failed to set file permissions");
+			}
+
+			match std::fs::copy(&src_path, &full_path) {
+				Ok(_) => {},
+				Err(e) => {
+					return Err(format!("failed to copy {} to {}: {}", path.display(), full_path.display(), e));
+				}
+			}
+		}
+
+		files += path.to_str().unwrap();
+		files.push('\n');
+	}
+
+	let mut removed = 0;
+	for name in installed.lines().rev()
+		.filter(|name| !files.lines().any(|line| line == *name)) {
+		let name = name.trim();
+		if name.is_empty() {
+			continue;
+		}
+
+		remove_path(root.join(name));
+		removed += 1;
+	}
+
+	if let Some(epoch) = epoch {
+		for name in files.lines().rev() {
+			reproducible::clamp_mtime(&root.join(name), epoch);
+		}
+	}
+
+	let total = files.lines().count();
+	let added = files.lines()
+		.filter(|name| !installed.lines().any(|line| line == *name))
+		.count();
+
+	create_dir(pkg_meta_dir);
+	write_file(&pkg_meta_dir.join("FILES"), files);
+
+	Ok((added, removed, total))
+}
+
+fn installed_packages(meta_dir: &Path) -> Vec<String> {
+	let Ok(dir) = read_dir(meta_dir) else {
+		return Vec::new();
+	};
+
+	let mut names: Vec<_> = dir.flatten()
+		.filter(|entry| entry.path().join("FILES").exists())
+		.filter_map(|entry| entry.file_name().into_string().ok())
+		.collect();
+	names.sort();
+	names
+}
+
+// the installed packages outside of removing that depend on name
+fn reverse_depends(meta_dir: &Path, name: &str, removing: &[String]) -> Vec<String> {
+	installed_packages(meta_dir)
+		.into_iter()
+		.filter(|pkg| !removing.contains(pkg))
+		.filter(|pkg| read_list(&meta_dir.join(pkg), "DEPENDS").iter().any(|dep| dep == name))
+		.collect()
+}
+
+// removes the files recorded in meta_dir from root
+pub fn uninstall(meta_dir: &Path, root: &Path, name: &str) {
+	let pkg_meta_dir = meta_dir.join(name);
+
+	let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
+
+	for name in installed.lines().rev() {
+		let name = name.trim();
+		if name.is_empty() {
+			continue;
+		}
+
+		let path = root.join(name);
+		remove_path(&path);
+	}
+
+	if let Err(e) = std::fs::remove_dir_all(&pkg_meta_dir) {
+		output::error(format_args!("failed to remove {}: {}", pkg_meta_dir.display(), e));
+		exit(1);
+	}
+}
+
+pub fn remove(meta_dir: &Path, sysroot: &Path, names: &[String], cascade: bool, force: bool) {
+	let mut removing = Vec::new();
+	for name in names {
+		match meta_dir.join(name).try_exists() {
+			Ok(true) => removing.push(name.clone()),
+			Ok(false) => output::info(format_args!("package {} is not installed", name)),
+			Err(e) => {
+				output::error(format_args!("failed to determine if {} is installed: {}", name, e));
+				exit(1);
+			}
+		}
+	}
+
+	if !force {
+		let mut i = 0;
+		while i < removing.len() {
+			let needed_by = reverse_depends(meta_dir, &removing[i], &removing);
+			if !needed_by.is_empty() {
+				if !cascade {
+					output::error(format_args!(
+						"{} is needed by {}, use --cascade to remove them too or --force to remove it anyway",
+						removing[i],
+						needed_by.join(" ")));
+					exit(1);
+				}
+				removing.extend(needed_by);
+			}
+			i += 1;
+		}
+	}
+
+	// dependents were appended after their dependencies
+	for name in removing.iter().rev() {
+		output::info(format_args!("removing {}", name));
+		uninstall(meta_dir, sysroot, name);
+	}
+}
+
+// host packages are removed from the host prefix if they were synced to one and their install is
+// reset so that it's redone the next time they're needed
+pub fn remove_host(build_root: &Path, host_prefix: Option<&Path>, names: &[String]) {
+	let host_meta_dir = build_root.join("host_meta");
+
+	for name in names {
+		let dest_dir = build_root.join("host_pkgs").join(name);
+		let synced = host_meta_dir.join(name).exists();
+		if !synced && !dest_dir.exists() {
+			output::info(format_args!("package {} is not installed", display_name(name, true)));
+			continue;
+		}
+
+		output::info(format_args!("removing {}", display_name(name, true)));
+
+		if let Some(host_prefix) = host_prefix && synced {
+			uninstall(&host_meta_dir, host_prefix, name);
+		}
+		if dest_dir.exists() && let Err(e) = std::fs::remove_dir_all(&dest_dir) {
+			output::error(format_args!("failed to remove {}: {}", dest_dir.display(), e));
+			exit(1);
+		}
+		remove_file(build_root.join("host_builds").join(name).join("qpkg.installed"));
+	}
+}
+
+// removes dependency packages until no installed package needs the remaining ones
+pub fn autoremove(meta_dir: &Path, sysroot: &Path) {
+	let mut removed = 0;
+
+	loop {
+		let unneeded: Vec<_> = installed_packages(meta_dir)
+			.into_iter()
+			.filter(|pkg| !is_explicit(&meta_dir.join(pkg)))
+			.filter(|pkg| reverse_depends(meta_dir, pkg, &[]).is_empty())
+			.collect();
+		if unneeded.is_empty() {
+			break;
+		}
+
+		for name in &unneeded {
+			output::info(format_args!("removing {}", name));
+			uninstall(meta_dir, sysroot, name);
+		}
+		removed += unneeded.len();
+	}
+
+	if removed == 0 {
+		output::info("no packages to remove");
+	}
+}
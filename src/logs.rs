@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, ExitStatus, Stdio};
+use crate::create_dir;
+use crate::output::{self, Format};
+
+pub fn log_path(build_root: &Path, name: &str, host: bool, step: &str) -> PathBuf {
+	let dir = if host {
+		build_root.join("host_logs")
+	} else {
+		build_root.join("logs")
+	};
+	dir.join(name).join(format!("{}.log", step))
+}
+
+pub fn open_log(path: &Path) -> File {
+	create_dir(path.parent().unwrap());
+	match File::create(path) {
+		Ok(file) => file,
+		Err(e) => {
+			output::error(format_args!("failed to create {}: {}", path.display(), e));
+			exit(1);
+		}
+	}
+}
+
+fn tee(mut from: impl Read, mut to: impl Write, mut log: File) {
+	let mut buf = [0; 4096];
+	loop {
+		let len = match from.read(&mut buf) {
+			Ok(0) | Err(_) => break,
+			Ok(len) => len
+		};
+		to.write_all(&buf[..len]).ok();
+		to.flush().ok();
+		log.write_all(&buf[..len]).ok();
+	}
+}
+
+// a pseudo terminal of the same size as ours so that the child still colours its output and
+// draws progress bars, returns the master and slave side
+fn pty() -> Option<(File, File)> {
+	let stdout = std::io::stdout();
+	if output::format() != Format::Text || !stdout.is_terminal() {
+		return None;
+	}
+
+	let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+	let (mut master, mut slave) = (0, 0);
+	unsafe {
+		libc::ioctl(stdout.as_raw_fd(), libc::TIOCGWINSZ, &mut size);
+		if libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), &size) != 0 {
+			return None;
+		}
+		libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC);
+		Some((File::from_raw_fd(master), File::from_raw_fd(slave)))
+	}
+}
+
+// runs the command while copying its output to both the terminal and the log
+pub fn run(cmd: &mut Command, log: &File) -> std::io::Result<ExitStatus> {
+	if let Some((master, slave)) = pty() {
+		let slave_err = slave.try_clone().expect("failed to duplicate pty");
+		let child = cmd
+			.stdout(slave)
+			.stderr(slave_err)
+			.spawn();
+		// the command keeps its stdio around, the master only sees the end of the output once
+		// every copy of the slave side is closed
+		cmd.stdout(Stdio::null()).stderr(Stdio::null());
+		let mut child = child?;
+
+		tee(master, std::io::stdout(), log.try_clone().expect("failed to duplicate log file"));
+		return child.wait();
+	}
+
+	let mut child = cmd
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+
+	let stdout = child.stdout.take().unwrap();
+	let stderr = child.stderr.take().unwrap();
+	let stdout_log = log.try_clone().expect("failed to duplicate log file");
+	let stderr_log = log.try_clone().expect("failed to duplicate log file");
+
+	let json = output::format() == Format::Json;
+	let stdout_thread = std::thread::spawn(move || {
+		if json {
+			tee(stdout, std::io::stderr(), stdout_log);
+		} else {
+			tee(stdout, std::io::stdout(), stdout_log);
+		}
+	});
+	let stderr_thread = std::thread::spawn(move || tee(stderr, std::io::stderr(), stderr_log));
+
+	stdout_thread.join().ok();
+	stderr_thread.join().ok();
+
+	child.wait()
+}
+
+// why a command run through run failed, none if it succeeded
+pub fn failure(status: &std::io::Result<ExitStatus>, program: &str) -> Option<String> {
+	match status {
+		Ok(status) if status.success() => None,
+		Ok(status) => Some(format!("{} failed with {}", program, status)),
+		Err(e) => Some(format!("failed to run {}: {}", program, e))
+	}
+}
@@ -1,19 +1,45 @@
 #![feature(io_error_more)]
 
 mod build;
+mod bump;
+mod clean;
+mod cli;
+mod git;
+mod installed;
+mod logs;
+mod output;
+mod patches;
+mod plan;
+mod refresh;
+mod reproducible;
+mod sandbox;
+mod scaffold;
 mod template;
+mod timing;
+mod tree;
+mod watch;
 
 use std::collections::HashMap;
+use std::fs::File;
 use std::fs::{create_dir_all, read_to_string, write};
-use std::os::unix::fs::PermissionsExt;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::time::Instant;
 use aho_corasick::AhoCorasick;
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
 use serde::Deserialize;
 use toml::Value;
 use walkdir::WalkDir;
 use crate::build::Step;
-use crate::template::{Template, Templates};
+use crate::clean::CleanOptions;
+use crate::cli::{Cli, RunArgs, StepArgs};
+use crate::output::{Event, FailedPackage, Format};
+use crate::plan::{PackagePlan, SyncPlan};
+use crate::sandbox::Sandbox;
+use crate::template::{Template, TemplateCommand, Templates};
+use crate::timing::Timer;
 
 fn yes() -> bool {
 	true
@@ -23,6 +49,14 @@ fn default_doc_dir() -> String {
 	"/usr/share/doc".to_string()
 }
 
+fn default_shell() -> String {
+	"/bin/sh".to_string()
+}
+
+fn default_env_allowlist() -> Vec<String> {
+	["HOME", "TERM", "USER"].map(String::from).to_vec()
+}
+
 #[derive(Deserialize)]
 struct GeneralConfig {
 	target: String,
@@ -42,6 +76,20 @@ struct GeneralConfig {
 	strip_docs: bool,
 	#[serde(default = "default_doc_dir")]
 	doc_dir: String,
+	#[serde(default)]
+	sandbox: bool,
+	#[serde(default)]
+	env_clear: bool,
+	// runs the commands in string form
+	#[serde(default = "default_shell")]
+	shell: String,
+	#[serde(default = "default_env_allowlist")]
+	env_allowlist: Vec<String>,
+	#[serde(default)]
+	reproducible: bool,
+	#[serde(default)]
+	clamp_mtimes: bool,
+	host_prefix: Option<String>,
 	#[serde(flatten)]
 	others: HashMap<String, String>
 }
@@ -56,6 +104,12 @@ struct BuildConfig {
 	cxxflags: String,
 	#[serde(default)]
 	ldflags: String,
+	// a command the tests are run through, e.g. an emulator for the target
+	#[serde(default)]
+	test_runner: String,
+	// applied before the env of the templates and recipes
+	#[serde(default)]
+	env: Vec<build::EnvEntry>,
 	#[serde(flatten)]
 	others: HashMap<String, String>
 }
@@ -68,6 +122,8 @@ impl Default for BuildConfig {
 			cflags: "".to_string(),
 			cxxflags: "".to_string(),
 			ldflags: "".to_string(),
+			test_runner: "".to_string(),
+			env: Vec::new(),
 			others: HashMap::new()
 		}
 	}
@@ -81,6 +137,15 @@ struct Config {
 	target: BuildConfig
 }
 
+// maps packages to checkouts that are built instead of their sources, meant to stay out of git
+#[derive(Deserialize, Default)]
+struct LocalConfig {
+	#[serde(default)]
+	sources: HashMap<String, PathBuf>,
+	#[serde(default)]
+	host_sources: HashMap<String, PathBuf>
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum Op {
 	Prepare,
@@ -90,54 +155,50 @@ enum Op {
 	Sync
 }
 
-fn usage() -> ! {
-	eprintln!(r"usage: qpkg <ops>... <args>... <names>...
-op:
-    prepare
-    configure
-    build
-    install
-    remove
-    sync
-    gen-patch <patch name> <pattern_to_add>
-
-    rebuild     equivalent to build install sync --force
-args:
-    --force
-    --host
-    --env=<name>=<value>
-    --dev      initialize a git repository during prepare if one doesn't exist
-    --config=<path_to_qpkg.toml>");
-	exit(1);
-}
+fn finalize_config(mut config: Config, path: &str) -> (Config, String) {
+	let abs = std::path::absolute(path)
+		.expect("failed to get absolute config path");
+	let config_parent_dir = abs.parent().unwrap();
 
-fn load_config(path: String) -> (Config, String) {
-	let finalize_config = |mut config: Config, path: &str| {
-		let abs = std::path::absolute(path)
-			.expect("failed to get absolute config path");
-		let config_parent_dir = abs.parent().unwrap();
-
-		let make_abs = |path: &mut String| {
-			let actual_path = Path::new(path);
-			if actual_path.is_relative() {
-				*path = config_parent_dir.join(path.as_str()).to_str().unwrap().to_string();
-			}
-		};
+	let make_abs = |path: &mut String| {
+		let actual_path = Path::new(path);
+		if actual_path.is_relative() {
+			*path = config_parent_dir.join(path.as_str()).to_str().unwrap().to_string();
+		}
+	};
 
-		make_abs(&mut config.general.sysroot);
-		make_abs(&mut config.general.recipes_dir);
-		make_abs(&mut config.general.host_recipes_dir);
-		make_abs(&mut config.general.meta_dir);
-		make_abs(&mut config.general.build_root);
+	make_abs(&mut config.general.sysroot);
+	make_abs(&mut config.general.recipes_dir);
+	make_abs(&mut config.general.host_recipes_dir);
+	make_abs(&mut config.general.meta_dir);
+	make_abs(&mut config.general.build_root);
+	if let Some(host_prefix) = &mut config.general.host_prefix {
+		make_abs(host_prefix);
+	}
 
-		(config, path.to_string())
-	};
+	for entry in config.build.env.iter_mut().chain(&mut config.target.env) {
+		for value in entry.ops.iter_mut().filter_map(build::EnvOp::value_mut) {
+			*value = value.replace("@SYSROOT@", &config.general.sysroot)
+				.replace("@BUILDROOT@", &config.general.build_root);
+		}
+	}
 
+	(config, path.to_string())
+}
+
+// completion runs on every tab press so a missing or broken config just gives no candidates
+fn try_load_config(path: &str) -> Option<Config> {
+	let data = read_to_string(path).ok()?;
+	let config = toml::from_str::<Config>(&data).ok()?;
+	Some(finalize_config(config, path).0)
+}
+
+fn load_config(path: String) -> (Config, String) {
 	if !path.is_empty() {
 		let data = match read_to_string(&path) {
 			Ok(data) => data,
 			Err(e) => {
-				eprintln!("error: failed to read {}: {}", path, e);
+				output::error(format_args!("failed to read {}: {}", path, e));
 				exit(1);
 			}
 		};
@@ -147,7 +208,7 @@ fn load_config(path: String) -> (Config, String) {
 				finalize_config(config, &path)
 			},
 			Err(e) => {
-				eprintln!("error: failed to parse config: {}", e);
+				output::error(format_args!("failed to parse config: {}", e));
 				exit(1);
 			}
 		}
@@ -166,13 +227,13 @@ fn load_config(path: String) -> (Config, String) {
 					finalize_config(config, path)
 				},
 				Err(e) => {
-					eprintln!("error: failed to parse config {}: {}", path, e);
+					output::error(format_args!("failed to parse config {}: {}", path, e));
 					exit(1);
 				}
 			};
 		}
 
-		eprintln!("error: failed to find qpkg.toml in the current directory or in /etc");
+		output::error("failed to find qpkg.toml in the current directory or in /etc");
 		exit(1);
 	}
 }
@@ -185,7 +246,7 @@ fn load_templates(config_path: &str, templates_file: &str) -> Templates {
 	let data = match read_to_string(&path) {
 		Ok(data) => data,
 		Err(e) => {
-			eprintln!("error: failed to read {}: {}", path.display(), e);
+			output::error(format_args!("failed to read {}: {}", path.display(), e));
 			exit(1);
 		}
 	};
@@ -195,10 +256,41 @@ fn load_templates(config_path: &str, templates_file: &str) -> Templates {
 			templates
 		},
 		Err(e) => {
-			eprintln!("error: failed to parse template file {}: {}", templates_file, e);
+			output::error(format_args!("failed to parse template file {}: {}", templates_file, e));
+			exit(1);
+		}
+	}
+}
+
+fn load_local_config(config_parent_dir: &Path) -> LocalConfig {
+	let path = config_parent_dir.join("qpkg.local.toml");
+	let data = match read_to_string(&path) {
+		Ok(data) => data,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return LocalConfig::default(),
+		Err(e) => {
+			output::error(format_args!("failed to read {}: {}", path.display(), e));
+			exit(1);
+		}
+	};
+
+	let mut local = match toml::from_str::<LocalConfig>(&data) {
+		Ok(local) => local,
+		Err(e) => {
+			output::error(format_args!("failed to parse {}: {}", path.display(), e));
+			exit(1);
+		}
+	};
+
+	for (name, source) in local.sources.iter_mut().chain(local.host_sources.iter_mut()) {
+		*source = std::path::absolute(config_parent_dir.join(&source))
+			.expect("failed to get absolute local source path");
+		if !source.is_dir() {
+			output::error(format_args!("the local source {} of {} is not a directory", source.display(), name));
 			exit(1);
 		}
 	}
+
+	local
 }
 
 fn load_recipe(config: &Config, name: &str, host: bool) -> build::Recipe {
@@ -211,7 +303,7 @@ fn load_recipe(config: &Config, name: &str, host: bool) -> build::Recipe {
 	let data = match read_to_string(&path) {
 		Ok(data) => data,
 		Err(e) => {
-			eprintln!("error: failed to read recipe {}: {}", path.display(), e);
+			output::error(format_args!("failed to read recipe {}: {}", path.display(), e));
 			exit(1);
 		}
 	};
@@ -219,7 +311,7 @@ fn load_recipe(config: &Config, name: &str, host: bool) -> build::Recipe {
 	match toml::from_str(&data) {
 		Ok(config) => config,
 		Err(e) => {
-			eprintln!("error: failed to parse recipe: {}", e);
+			output::error(format_args!("failed to parse recipe: {}", e));
 			exit(1);
 		}
 	}
@@ -228,17 +320,28 @@ fn load_recipe(config: &Config, name: &str, host: bool) -> build::Recipe {
 struct State {
 	config: Config,
 	config_parent_dir: PathBuf,
-	templates: HashMap<String, Template>
+	templates: HashMap<String, Template>,
+	local: LocalConfig
 }
 
 impl State {
 	fn new(config: Config, config_parent_dir: PathBuf, templates: HashMap<String, Template>) -> Self {
+		let local = load_local_config(&config_parent_dir);
 		Self {
 			config,
 			config_parent_dir,
-			templates
+			templates,
+			local
 		}
 	}
+
+	fn local_source(&self, name: &str, host: bool) -> Option<&Path> {
+		if host {
+			self.local.host_sources.get(name)
+		} else {
+			self.local.sources.get(name)
+		}.map(PathBuf::as_path)
+	}
 }
 
 fn finalize_recipe(
@@ -252,7 +355,7 @@ fn finalize_recipe(
 		let template = if let Some(template) = state.templates.get(name) {
 			template
 		} else {
-			eprintln!("error: use of undefined template {} in recipe for {}", name, recipe.general.name);
+			output::error(format_args!("use of undefined template {} in recipe for {}", name, recipe.general.name));
 			exit(1);
 		};
 
@@ -261,26 +364,48 @@ fn finalize_recipe(
 		recipe.general.depends.extend(template.depends.iter().cloned());
 		recipe.general.host_depends.extend(template.host_depends.iter().cloned());
 
-		recipe.prepare.args.extend(template.add_prepare.iter().cloned().map(|s| vec![s]));
-		recipe.configure.args.extend(template.add_configure.iter().cloned().map(|s| vec![s]));
-		recipe.build.args.extend(template.add_build.iter().cloned().map(|s| vec![s]));
-		recipe.install.args.extend(template.add_install.iter().cloned().map(|s| vec![s]));
+		recipe.prepare.args.extend(template.add_prepare.iter().cloned().map(build::Command::from));
+		recipe.configure.args.extend(template.add_configure.iter().cloned().map(build::Command::from));
+		recipe.build.args.extend(template.add_build.iter().cloned().map(build::Command::from));
+		recipe.install.args.extend(template.add_install.iter().cloned().map(build::Command::from));
+		recipe.check.args.extend(template.add_check.iter().cloned().map(build::Command::from));
+
+		// the recipe goes last so that it can override or extend the template
+		recipe.prepare.env.splice(0..0, template.prepare_env.iter().cloned());
+		recipe.configure.env.splice(0..0, template.configure_env.iter().cloned());
+		recipe.build.env.splice(0..0, template.build_env.iter().cloned());
+		recipe.install.env.splice(0..0, template.install_env.iter().cloned());
+		recipe.check.env.splice(0..0, template.check_env.iter().cloned());
+
+		for (hook, commands) in [
+			(&mut recipe.pre_prepare, &template.pre_prepare),
+			(&mut recipe.post_prepare, &template.post_prepare),
+			(&mut recipe.pre_configure, &template.pre_configure),
+			(&mut recipe.post_configure, &template.post_configure),
+			(&mut recipe.pre_build, &template.pre_build),
+			(&mut recipe.post_build, &template.post_build),
+			(&mut recipe.pre_install, &template.pre_install),
+			(&mut recipe.post_install, &template.post_install)] {
+			hook.args.extend(commands.iter().cloned().map(build::Command::from));
+		}
 
-		recipe.prepare.env.extend(template.prepare_env.iter().cloned());
-		recipe.configure.env.extend(template.configure_env.iter().cloned());
-		recipe.build.env.extend(template.build_env.iter().cloned());
-		recipe.install.env.extend(template.install_env.iter().cloned());
+		for step in &template.steps {
+			if !recipe.steps.iter().any(|other| other.name == step.name) {
+				recipe.steps.push(step.clone());
+			}
+		}
 
 		let parse_template_args = |config: &Value, name: &str| {
 			if let Value::Array(array) = config {
-				array.iter().map(|value| if let Value::String(value) = value {
-					vec![value.clone()]
-				} else {
-					eprintln!("error: expected an array of strings in template step {}", name);
-					exit(1);
+				array.iter().map(|value| match value.clone().try_into::<TemplateCommand>() {
+					Ok(command) => command.into(),
+					Err(_) => {
+						output::error(format_args!("expected an array of strings or argv tables in template step {}", name));
+						exit(1);
+					}
 				}).collect::<Vec<_>>()
 			} else {
-				eprintln!("error: expected an array of strings in template step {}", name);
+				output::error(format_args!("expected an array of strings or argv tables in template step {}", name));
 				exit(1);
 			}
 		};
@@ -293,7 +418,7 @@ fn finalize_recipe(
 			let config = if let Some(config) = template.others.get(template_name) {
 				config
 			} else {
-				eprintln!("error: default_{} specified an undefined key {}", step_name, template_name);
+				output::error(format_args!("default_{} specified an undefined key {}", step_name, template_name));
 				exit(1);
 			};
 
@@ -304,6 +429,12 @@ fn finalize_recipe(
 		apply_template_step(&mut recipe.configure, "configure", &template.default_configure);
 		apply_template_step(&mut recipe.build, "build", &template.default_build);
 		apply_template_step(&mut recipe.install, "install", &template.default_install);
+		apply_template_step(&mut recipe.check, "check", &template.default_check);
+	}
+
+	if let Err(e) = recipe.check_steps() {
+		output::error(format_args!("in recipe for {}: {}", recipe.general.name, e));
+		exit(1);
 	}
 
 	recipe.general.workdir = recipe.general.workdir.replace("@VERSION@", recipe.general.version.as_str());
@@ -329,6 +460,11 @@ fn finalize_recipe(
 	let sysroot_dir = std::path::absolute(&state.config.general.sysroot)
 		.expect("failed to make sysroot absolute");
 
+	// the steps are replaced in place so the values they're replaced with can't borrow the recipe
+	let name = recipe.general.name.clone();
+	let version = recipe.general.version.clone();
+	let recipe_others = recipe.general.others.clone();
+
 	let mut to_replace = Vec::from([
 		"@VERSION@",
 		"@BUILDROOT@",
@@ -340,7 +476,7 @@ fn finalize_recipe(
 		"\n"
 	]);
 	let mut replaces = Vec::from([
-		recipe.general.version.as_str(),
+		version.as_str(),
 		build_root_dir.to_str().unwrap(),
 		src_dir.to_str().unwrap(),
 		dest_dir.to_str().unwrap(),
@@ -357,7 +493,7 @@ fn finalize_recipe(
 		replaces.push(replace);
 	}
 
-	for (name, replace) in &recipe.general.others {
+	for (name, replace) in &recipe_others {
 		to_replace_strings.push(format!("@{}@", name.to_uppercase()));
 		replaces.push(replace);
 	}
@@ -373,6 +509,13 @@ fn finalize_recipe(
 		to_replace.push(to_replace_string);
 	}
 
+	// the arguments of an argv are kept exactly as they're written, newlines included
+	let exec_replaces: Vec<_> = to_replace.iter()
+		.zip(&replaces)
+		.filter(|(pattern, _)| **pattern != "\n")
+		.map(|(_, replace)| *replace)
+		.collect();
+	let exec_aho = AhoCorasick::new(to_replace.iter().filter(|pattern| **pattern != "\n")).unwrap();
 	let aho = AhoCorasick::new(to_replace).unwrap();
 
 	for src in &mut recipe.general.src {
@@ -381,16 +524,20 @@ fn finalize_recipe(
 
 	recipe.general.workdir = aho.replace_all(&recipe.general.workdir, &replaces);
 
-	for step in [
-		&mut recipe.prepare,
-		&mut recipe.configure,
-		&mut recipe.build,
-		&mut recipe.install] {
+	for step in recipe.steps_mut() {
 		loop {
 			let mut changed = false;
-			for list in &mut step.args {
-				for arg in list {
-					let result = aho.replace_all(arg.trim(), &replaces);
+			for command in &mut step.args {
+				let (args, aho, replaces, trim) = match command {
+					build::Command::Shell(parts) => (parts, &aho, &replaces, true),
+					build::Command::Exec { argv } if argv.is_empty() => {
+						output::error(format_args!("in recipe for {}: an argv is empty", name));
+						exit(1);
+					},
+					build::Command::Exec { argv } => (argv, &exec_aho, &exec_replaces, false)
+				};
+				for arg in args {
+					let result = aho.replace_all(if trim { arg.trim() } else { arg }, replaces);
 					if *arg != result {
 						*arg = result;
 						changed = true;
@@ -405,8 +552,8 @@ fn finalize_recipe(
 
 		loop {
 			let mut changed = false;
-			for env in &mut step.env {
-				for (_, value) in env {
+			for entry in &mut step.env {
+				for value in entry.ops.iter_mut().filter_map(build::EnvOp::value_mut) {
 					let result = aho.replace_all(value.trim(), &replaces);
 					if *value != result {
 						*value = result;
@@ -422,12 +569,39 @@ fn finalize_recipe(
 	}
 }
 
+fn recipes_dir(state: &State, host: bool) -> &Path {
+	if host {
+		Path::new(&state.config.general.host_recipes_dir)
+	} else {
+		Path::new(&state.config.general.recipes_dir)
+	}
+}
+
+// the package's source, build and dest dirs in that order
+fn package_dirs(build_root: &Path, name: &str, host: bool) -> [PathBuf; 3] {
+	let [sources, builds, pkgs] = if host {
+		["host_sources", "host_builds", "host_pkgs"]
+	} else {
+		["sources", "pkg_builds", "pkgs"]
+	};
+	[sources, builds, pkgs].map(|dir| build_root.join(dir).join(name))
+}
+
+fn load_finalized_recipe(state: &State, name: &str, host: bool) -> build::Recipe {
+	let build_root = Path::new(&state.config.general.build_root);
+	let [root_src_dir, _, dest_dir] = package_dirs(build_root, name, host);
+
+	let mut recipe = load_recipe(&state.config, name, host);
+	finalize_recipe(&mut recipe, state, &root_src_dir, &dest_dir);
+	recipe
+}
+
 fn touch_file(path: impl AsRef<Path>) {
 	let parent = path.as_ref().parent().unwrap();
 	match create_dir_all(parent) {
 		Ok(_) => {},
 		Err(e) => {
-			eprintln!("error: failed to create path {}: {}", parent.display(), e);
+			output::error(format_args!("failed to create path {}: {}", parent.display(), e));
 			exit(1);
 		}
 	}
@@ -439,7 +613,7 @@ fn remove_file(path: impl AsRef<Path>) {
 		Ok(_) => {},
 		Err(e) => {
 			if e.kind() != std::io::ErrorKind::NotFound {
-				eprintln!("error: failed to remove {}: {}", path.as_ref().display(), e);
+				output::error(format_args!("failed to remove {}: {}", path.as_ref().display(), e));
 				exit(1);
 			}
 		}
@@ -454,7 +628,7 @@ fn remove_path(path: impl AsRef<Path>) {
 				remove_file(path.as_ref());
 			} else if e.kind() != std::io::ErrorKind::NotFound &&
 				e.kind() != std::io::ErrorKind::DirectoryNotEmpty {
-				eprintln!("error: failed to remove {}: {}", path.as_ref().display(), e);
+				output::error(format_args!("failed to remove {}: {}", path.as_ref().display(), e));
 				exit(1);
 			}
 		}
@@ -465,12 +639,19 @@ fn create_dir(path: impl AsRef<Path>) {
 	match create_dir_all(path.as_ref()) {
 		Ok(_) => {},
 		Err(e) => {
-			eprintln!("error: failed to create directory {}: {}", path.as_ref().display(), e);
+			output::error(format_args!("failed to create directory {}: {}", path.as_ref().display(), e));
 			exit(1);
 		}
 	}
 }
 
+// the archives prepare can unpack, everything else is only downloaded
+const TARBALL_EXTENSIONS: [&str; 5] = [".tar.xz", ".tar.gz", ".tar.bz2", ".tar.zst", ".tgz"];
+
+fn is_tarball(src: &str) -> bool {
+	TARBALL_EXTENSIONS.iter().any(|ext| src.ends_with(ext))
+}
+
 fn source_to_name(src: &str) -> &str {
 	if let Some((_, name)) = src.rsplit_once('/') {
 		if let Some(pos) = name.find(".git") {
@@ -483,247 +664,711 @@ fn source_to_name(src: &str) -> &str {
 	}
 }
 
-fn main() {
-	let args: Vec<_> = std::env::args().skip(1).collect();
+// git clones are kept per ref so that a new version is cloned instead of the old clone being used
+fn archive_name(src: &str) -> String {
+	let name = source_to_name(src);
+	let branch = src.find(".git:")
+		.map(|pos| &src[pos + 5..])
+		.and_then(|opts| opts.split(',').next())
+		.unwrap_or_default();
+	if branch.is_empty() {
+		name.to_string()
+	} else {
+		format!("{}-{}", name, branch.replace('/', "_"))
+	}
+}
 
-	if args.len() < 2 {
-		usage();
+fn source_path(recipe: &build::Recipe, archives_dir: &Path, src: &str) -> PathBuf {
+	if !recipe.general.src_unpack_dir.is_empty() {
+		Path::new(&recipe.general.src_unpack_dir).join(source_to_name(src))
+	} else {
+		archives_dir.join(archive_name(src))
 	}
+}
 
-	let mut parsing_ops = true;
-	let mut force = false;
-	let mut host = false;
-	let mut remove = false;
-	let mut gen_patch = false;
-	let mut gen_patch_name = "";
-	let mut gen_patch_pattern = "";
-	let mut dev = false;
-	let mut ops = Vec::new();
-	let mut names = Vec::new();
-	let mut config_path = String::new();
-
-	let mut global_env = Vec::new();
-
-	let mut arg_i = 0;
-	while arg_i < args.len() {
-		let arg = &args[arg_i];
-		if parsing_ops {
-			match arg.as_str() {
-				"prepare" => ops.push(Op::Prepare),
-				"configure" => ops.push(Op::Configure),
-				"build" => ops.push(Op::Build),
-				"rebuild" => {
-					ops.push(Op::Build);
-					ops.push(Op::Install);
-					ops.push(Op::Sync);
-					force = true;
-				},
-				"install" => ops.push(Op::Install),
-				"remove" => remove = true,
-				"sync" => ops.push(Op::Sync),
-				"gen-patch" => {
-					if arg_i == args.len() - 1 {
-						eprintln!("error: gen-patch needs a patch name");
-						exit(1);
-					}
-					gen_patch_name = &args[arg_i + 1];
-					if gen_patch_name.is_empty() || gen_patch_name.contains("\t\n ") {
-						eprintln!("error: name given to gen-patch should not be empty or contain spaces");
-						exit(1);
-					}
-					arg_i += 1;
-					if arg_i == args.len() - 1 {
-						eprintln!("error: gen-patch needs a pattern to add");
-						exit(1);
-					}
+// the error if the archive doesn't match the checksum in the recipe
+fn checksum_mismatch(recipe: &build::Recipe, name: &str, path: &Path) -> Option<String> {
+	let expected = recipe.general.checksums.get(name).filter(|_| path.is_file())?;
+	let actual = reproducible::hash_file(path);
+	(actual != *expected).then(|| format!("checksum mismatch for {}: expected {} but got {}", name, expected, actual))
+}
 
-					gen_patch = true;
-					gen_patch_pattern = &args[arg_i + 1];
-					arg_i += 1;
-				},
-				"--force" => force = true,
-				"--host" => host = true,
-				"--dev" => dev = true,
-				arg if arg.starts_with("--config=") => {
-					config_path = arg.strip_prefix("--config=").unwrap().to_string();
-				}
-				arg if arg.starts_with("--env=") => {
-					let (name, value) = arg
-						.strip_prefix("--env=")
-						.unwrap()
-						.split_once('=')
-						.unwrap();
-					global_env.push((name.to_string(), value.to_string()));
-				}
-				arg if arg.starts_with("-") => {
-					eprintln!("error: unsupported argument {}", arg);
-					usage();
-				}
-				_ => parsing_ops = false
+// the command that fetches src to path, the method it uses and the url that is fetched
+fn fetch_command<'a>(src: &'a str, path: &Path, recurse_submodules: bool) -> Option<(Command, &'static str, &'a str)> {
+	if let Some(pos) = src.find(".git") {
+		let mut full = false;
+		let mut branch = "";
+
+		if pos + 4 != src.len() && &src[pos + 4..pos + 5] == ":" {
+			let opts = &src[pos + 5..];
+			if let Some(pos) = opts.find(",full") {
+				branch = &opts[0..pos];
+				full = true;
+			} else {
+				branch = opts;
 			}
 		}
 
-		if !parsing_ops {
-			names.push(arg.clone());
-		}
+		let branch_args = ["-b", branch];
 
-		arg_i += 1;
+		let mut git = Command::new("git");
+		git.arg("clone")
+			.arg(&src[0..pos])
+			.args(if !full {
+				["--depth=1"].as_slice()
+			} else {
+				[].as_slice()
+			})
+			.args(if !branch.is_empty() {
+				branch_args.as_slice()
+			} else {
+				[].as_slice()
+			})
+			.args(if recurse_submodules {
+				["--recurse-submodules"].as_slice()
+			} else {
+				[].as_slice()
+			})
+			.arg(path.to_str().unwrap());
+		Some((git, "git", &src[0..pos]))
+	} else if src.starts_with("http") {
+		let mut wget = Command::new("wget");
+		wget.arg(src).args(["-O", path.to_str().unwrap()]);
+		Some((wget, "wget", src))
+	} else {
+		None
 	}
+}
 
-	if remove {
-		if !ops.is_empty() {
-			eprintln!("error: multiple operations specified with remove");
-			exit(1);
-		}
-	} else if gen_patch {
-		if !ops.is_empty() {
-			eprintln!("error: multiple operations specified with gen-patch");
-			exit(1);
-		}
-	} else {
-		if ops.is_empty() {
-			eprintln!("error: no operations specified");
+// the workdir is a symlink to the local source or to a git clone, anything else means the
+// source was switched since it was prepared
+fn prepared_source_changed(recipe: &build::Recipe, archives_dir: &Path, root_src_dir: &Path, local_src: Option<&Path>) -> bool {
+	let link = std::fs::read_link(root_src_dir.join(&recipe.general.workdir)).ok();
+	match local_src {
+		Some(local_src) => link.as_deref() != Some(local_src),
+		None => link.is_some_and(|link| !recipe.general.src.iter()
+			.filter(|src| src.contains(".git"))
+			.any(|src| source_path(recipe, archives_dir, src).canonicalize().is_ok_and(|path| path == link)))
+	}
+}
+
+// whether a git source was edited since its steps last ran, the state is recorded after they ran
+// so that what they write into the source doesn't count
+fn source_modified(work_dir: &Path, build_dir: &Path) -> bool {
+	let recorded = read_to_string(build_dir.join("qpkg.source")).ok();
+	recorded.is_some_and(|recorded| tree::state(work_dir).is_some_and(|state| state != recorded))
+}
+
+fn record_source_state(work_dir: &Path, build_dir: &Path) {
+	if let Some(state) = tree::state(work_dir) && build_dir.exists() {
+		let path = build_dir.join("qpkg.source");
+		if let Err(e) = write(&path, state) {
+			output::error(format_args!("failed to write {}: {}", path.display(), e));
 			exit(1);
 		}
 	}
+}
 
-	if names.is_empty() {
-		eprintln!("error: no packages specified");
-		exit(1);
+fn step_stamp(root_src_dir: &Path, build_dir: &Path, step: &str) -> PathBuf {
+	match step {
+		"prepare" => root_src_dir.join("qpkg.prepared"),
+		"configure" => build_dir.join("qpkg.configured"),
+		"build" => build_dir.join("qpkg.built"),
+		"install" => build_dir.join("qpkg.installed"),
+		step => build_dir.join(format!("qpkg.step.{}", step))
 	}
+}
 
-	let (mut config, config_path) = load_config(config_path);
+// a named step runs again when the step it comes after ran since it last did
+fn step_outdated(root_src_dir: &Path, build_dir: &Path, step: &build::NamedStep) -> bool {
+	let modified = |step: &str| step_stamp(root_src_dir, build_dir, step).metadata().and_then(|meta| meta.modified()).ok();
+	match (modified(&step.name), modified(&step.after)) {
+		(Some(stamp), Some(after)) => stamp < after,
+		(Some(_), None) => false,
+		(None, _) => true
+	}
+}
 
-	let templates = if let Some(path) = &config.general.templates_file {
-		load_templates(&config_path, path).templates
+fn display_name(name: &str, host: bool) -> String {
+	if host {
+		format!("host:{}", name)
 	} else {
-		HashMap::new()
-	};
-
-	if config.general.threads == 0 {
-		config.general.threads = std::thread::available_parallelism()
-			.map(|num| num.get())
-			.unwrap_or(1);
+		name.to_string()
 	}
+}
 
-	let state = State::new(
-		config,
-		Path::new(&config_path).parent().unwrap().to_path_buf(),
-		templates);
+struct Failure {
+	name: String,
+	host: bool,
+	step: String,
+	log: PathBuf
+}
 
-	let meta_dir = state.config.general.meta_dir.clone();
-	let meta_dir = Path::new(&meta_dir);
+// host packages are used from the host prefix so they're synced whenever they're installed, the
+// dry run passes whether it plans to install the package as installed
+fn should_sync(
+	host: bool,
+	user_specified: bool,
+	already_synced: bool,
+	installed: bool,
+	local_src: bool,
+	do_sync: bool
+) -> bool {
+	if host {
+		installed || (!user_specified && !already_synced) || (user_specified && do_sync)
+	} else {
+		(!already_synced || (local_src && installed)) && (!user_specified || do_sync)
+	}
+}
 
-	let sysroot = Path::new(&state.config.general.sysroot);
+// exits unless --keep-going was given, in which case the caller skips the rest of the package
+fn package_failed(failures: &mut Vec<Failure>, keep_going: bool, name: &str, host: bool, step: &str, log: &Path) {
+	if !keep_going {
+		exit(1);
+	}
 
-	if remove {
-		for name in &names {
-			let pkg_meta_dir = meta_dir.join(name);
-			match pkg_meta_dir.try_exists() {
-				Ok(exists) => {
-					if exists {
-						println!("info: removing {}", name);
-					} else {
-						println!("info: package {} is not installed", name);
-						continue;
-					}
-				}
-				Err(e) => {
-					eprintln!("error: failed to determine if {} is installed: {}", name, e);
-					exit(1);
-				}
-			}
-	
-			let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
+	failures.push(Failure {
+		name: name.to_string(),
+		host,
+		step: step.to_string(),
+		log: log.to_path_buf()
+	});
+}
 
-			for name in installed.lines().rev() {
-				let name = name.trim();
-				if name.is_empty() {
-					continue;
-				}
+fn print_summary(built: &[(String, bool)], skipped: &[(String, bool)], failures: &[Failure]) {
+	let names = |list: &[(String, bool)]| list.iter()
+		.map(|(name, host)| display_name(name, *host))
+		.collect::<Vec<_>>();
+
+	if output::format() == Format::Json {
+		output::emit(Event::Summary {
+			built: names(built),
+			skipped: names(skipped),
+			failed: failures.iter().map(|failure| FailedPackage {
+				package: display_name(&failure.name, failure.host),
+				step: &failure.step,
+				log: failure.log.to_str().unwrap()
+			}).collect()
+		});
+		return;
+	}
 
-				let path = sysroot.join(name);
-				remove_path(&path);
-			}
+	println!("summary:");
+	println!("    built: {}", names(built).join(" "));
+	println!("    skipped: {}", names(skipped).join(" "));
+	println!("    failed:");
+	for failure in failures {
+		println!(
+			"        {} ({}), see {}",
+			display_name(&failure.name, failure.host),
+			failure.step,
+			failure.log.display());
+	}
+}
 
-			if let Err(e) = std::fs::remove_dir_all(&pkg_meta_dir) {
-				eprintln!("error: failed to remove {}: {}", pkg_meta_dir.display(), e);
-				exit(1);
-			}
-		}
+fn test_runner(state: &State, runner: &str) -> String {
+	let sysroot = std::path::absolute(&state.config.general.sysroot)
+		.expect("failed to make sysroot absolute");
+	runner.replace("@SYSROOT@", sysroot.to_str().unwrap())
+		.replace("@BUILDROOT@", &state.config.general.build_root)
+}
 
+fn print_test_summary(passed: &[(String, bool)], failures: &[Failure], untested: &[(String, bool)]) {
+	let names = |list: &[(String, bool)]| list.iter()
+		.map(|(name, host)| display_name(name, *host))
+		.collect::<Vec<_>>();
+
+	if output::format() == Format::Json {
+		output::emit(Event::TestSummary {
+			passed: names(passed),
+			failed: failures.iter().map(|failure| FailedPackage {
+				package: display_name(&failure.name, failure.host),
+				step: &failure.step,
+				log: failure.log.to_str().unwrap()
+			}).collect(),
+			untested: names(untested)
+		});
 		return;
 	}
 
-	let abs_host_cc = which::which(&state.config.build.cc)
-		.expect("failed to find build cc in PATH");
-	let abs_host_cxx = which::which(&state.config.build.cxx)
-		.expect("failed to find build cxx in PATH");
+	println!("tests:");
+	println!("    passed: {}", names(passed).join(" "));
+	if !untested.is_empty() {
+		println!("    no check step: {}", names(untested).join(" "));
+	}
+	println!("    failed:");
+	for failure in failures {
+		println!("        {}, see {}", display_name(&failure.name, failure.host), failure.log.display());
+	}
+}
 
-	let target_cc = state.config.target.cc.replace("@BUILDROOT@", &state.config.general.build_root);
-	let target_cxx = state.config.target.cc.replace("@BUILDROOT@", &state.config.general.build_root);
+fn finish_step(state: &State, name: &str, host: bool, step: &str, timer: &Timer) {
+	let timing = timer.finish(step);
+	output::emit(Event::StepFinished { package: name, step, duration: timing.wall });
+	timing::record(Path::new(&state.config.general.build_root), name, host, timing);
+}
 
-	global_env.push(("CC".to_string(), target_cc));
-	global_env.push(("CXX".to_string(), target_cxx));
-	global_env.push(("QPKG_HOST_CC".to_string(), abs_host_cc.to_str().unwrap().to_string()));
-	global_env.push(("QPKG_HOST_CXX".to_string(), abs_host_cxx.to_str().unwrap().to_string()));
-	if !state.config.target.cflags.is_empty() {
-		global_env.push(("CFLAGS".to_string(), state.config.target.cflags.clone()));
-	}
-	if !state.config.target.cxxflags.is_empty() {
-		global_env.push(("CXXFLAGS".to_string(), state.config.target.cxxflags.clone()));
+fn set_env(env: &mut Vec<(String, String)>, name: &str, value: &str) {
+	if let Some(entry) = env.iter_mut().find(|(existing, _)| existing == name) {
+		entry.1 = value.to_string();
+	} else {
+		env.push((name.to_string(), value.to_string()));
 	}
-	if !state.config.target.ldflags.is_empty() {
-		global_env.push(("LDFLAGS".to_string(), state.config.target.ldflags.clone()));
+}
+
+fn apply_env(env: &mut Vec<(String, String)>, op: &build::EnvOp) {
+	let current = |env: &[(String, String)], name: &str| env.iter()
+		.find(|(existing, _)| existing == name)
+		.map(|(_, value)| value.clone())
+		.filter(|value| !value.is_empty());
+
+	match op {
+		build::EnvOp::Set { name, value } => set_env(env, name, value),
+		build::EnvOp::Append { name, value, separator } => {
+			let value = match current(env, name) {
+				Some(current) => format!("{}{}{}", current, separator, value),
+				None => value.clone()
+			};
+			set_env(env, name, &value);
+		},
+		build::EnvOp::Prepend { name, value, separator } => {
+			let value = match current(env, name) {
+				Some(current) => format!("{}{}{}", value, separator, current),
+				None => value.clone()
+			};
+			set_env(env, name, &value);
+		},
+		build::EnvOp::Unset { name } => env.retain(|(existing, _)| existing != name)
 	}
-	for (name, value) in &state.config.target.others {
-		global_env.push((name.clone(), value.clone()));
+}
+
+// the complete environment the commands of a step are run with
+fn step_env(
+	state: &State,
+	host: bool,
+	global_env: &[(String, String)],
+	step: &Step,
+	path: &str,
+	aclocal: &str,
+	sysroot_dir: Option<&Path>) -> Vec<(String, String)> {
+	let mut env = Vec::new();
+
+	for (name, value) in std::env::vars_os() {
+		let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) else {
+			continue;
+		};
+		if !state.config.general.env_clear || state.config.general.env_allowlist.contains(&name) {
+			env.push((name, value));
+		}
 	}
 
-	let mut global_host_env = Vec::new();
-	global_host_env.push(("CC".to_string(), state.config.build.cc.clone()));
-	global_host_env.push(("CXX".to_string(), state.config.build.cxx.clone()));
-	if !state.config.build.cflags.is_empty() {
-		global_host_env.push(("CFLAGS".to_string(), state.config.build.cflags.clone()));
+	set_env(&mut env, "LC_ALL", "C");
+	for (name, value) in global_env {
+		set_env(&mut env, name, value);
 	}
-	if !state.config.build.cxxflags.is_empty() {
-		global_host_env.push(("CXXFLAGS".to_string(), state.config.build.cxxflags.clone()));
+	if let Some(sysroot_dir) = sysroot_dir {
+		set_env(&mut env, "QPKG_SYSROOT_DIR", sysroot_dir.to_str().unwrap());
 	}
-	if !state.config.build.ldflags.is_empty() {
-		global_host_env.push(("LDFLAGS".to_string(), state.config.build.ldflags.clone()));
+	set_env(&mut env, "PATH", path);
+	set_env(&mut env, "ACLOCAL_PATH", aclocal);
+
+	// the config, template and recipe in that order so that PATH and the like can be extended
+	let config = if host {
+		&state.config.build
+	} else {
+		&state.config.target
+	};
+	for entry in config.env.iter().chain(&step.env) {
+		for op in &entry.ops {
+			apply_env(&mut env, op);
+		}
 	}
+	env
+}
 
-	let mut force_prepare = false;
-	let mut force_configure = false;
-	let mut force_build = false;
-	let mut force_install = false;
+// the files of a dest dir that are synced to the sysroot, relative to the dest dir
+fn dest_files(state: &State, dest_dir: &Path) -> Vec<PathBuf> {
+	let doc_path = Path::new(&state.config.general.doc_dir);
+	let mut files = Vec::new();
 
-	let mut do_prepare = false;
-	let mut do_configure = false;
-	let mut do_build = false;
-	let mut do_install = false;
-	let mut do_sync = false;
+	for file in WalkDir::new(dest_dir) {
+		let file = file.unwrap();
+		let path = file.path().strip_prefix(dest_dir).unwrap();
 
-	if force {
-		for op in &ops {
-			match op {
-				Op::Prepare => force_prepare = true,
-				Op::Configure => force_configure = true,
-				Op::Build => force_build = true,
-				Op::Install => force_install = true,
-				Op::Sync => {}
-			}
+		if state.config.general.strip_la_files && path.extension().is_some_and(|ext| ext == "la") {
+			continue;
 		}
+		if state.config.general.strip_docs && path.starts_with(doc_path) {
+			continue;
+		}
+
+		files.push(path.to_path_buf());
 	}
 
-	for op in &ops {
-		match op {
-			Op::Prepare => do_prepare = true,
-			Op::Configure => do_configure = true,
-			Op::Build => do_build = true,
-			Op::Install => do_install = true,
-			Op::Sync => do_sync = true
-		}
+	files
+}
+
+fn start_shell(mut cmd: Command, cwd: &Path, env: &[(String, String)]) {
+	output::info(format_args!("starting a shell in {}", cwd.display()));
+
+	if let Err(e) = cmd
+		.current_dir(cwd)
+		.env_clear()
+		.envs(env.iter().cloned())
+		.status() {
+		output::error(format_args!("failed to spawn shell: {}", e));
+		exit(1);
+	}
+}
+
+fn main() {
+	CompleteEnv::with_factory(Cli::command).complete();
+
+	let cli = Cli::parse();
+	output::set_format(cli.output);
+
+	let host = cli.host;
+	let config_path = cli.config.unwrap_or_default();
+	// the --env variables only apply to target packages and come before the config's
+	let env_args = cli.env;
+	let mut global_env = env_args.clone();
+
+	let mut force = false;
+	let mut remove = false;
+	let mut cascade = false;
+	let mut autoremove = false;
+	let mut report = false;
+	let mut clean = None;
+	let mut gc = false;
+	let mut new_recipe = None;
+	let mut bump = None;
+	let mut patch_refresh = None;
+	let mut watch = None;
+	let mut print_env = false;
+	let mut open_shell = false;
+	let mut check_repro = false;
+	let mut vary_path = false;
+	let mut vary_umask = false;
+	let mut vary_time = false;
+	let mut dry_run = false;
+	let mut env_step = "build".to_string();
+	let mut gen_patch = false;
+	let mut gen_patch_name = String::new();
+	let mut gen_patch_pattern = String::new();
+	let mut gen_patch_series = false;
+	let mut run_step = None;
+	let mut test = false;
+	let mut run = RunArgs::default();
+	let mut ops = Vec::new();
+
+	let mut step_op = |op: Option<Op>, args: StepArgs| {
+		ops.extend(op);
+		if args.sync {
+			ops.push(Op::Sync);
+		}
+		force = args.force;
+		dry_run = args.dry_run;
+		run = args.run;
+		args.packages.names
+	};
+
+	let names = match cli.command {
+		cli::Command::Prepare(args) => step_op(Some(Op::Prepare), args),
+		cli::Command::Configure(args) => step_op(Some(Op::Configure), args),
+		cli::Command::Build(args) => step_op(Some(Op::Build), args),
+		cli::Command::Install(args) => step_op(Some(Op::Install), args),
+		cli::Command::Step(args) => {
+			let op = match args.step.as_str() {
+				"prepare" => Some(Op::Prepare),
+				"configure" => Some(Op::Configure),
+				"build" => Some(Op::Build),
+				"install" => Some(Op::Install),
+				_ => {
+					run_step = Some(args.step);
+					None
+				}
+			};
+			step_op(op, args.args)
+		},
+		cli::Command::Test(args) => {
+			ops.push(Op::Build);
+			test = true;
+			dry_run = args.dry_run;
+			run = args.run;
+			args.packages.names
+		},
+		cli::Command::Sync(args) => {
+			ops.push(Op::Sync);
+			dry_run = args.dry_run;
+			run = args.run;
+			args.packages.names
+		},
+		cli::Command::Rebuild(args) => {
+			ops.extend([Op::Build, Op::Install, Op::Sync]);
+			force = true;
+			dry_run = args.dry_run;
+			run = args.run;
+			args.packages.names
+		},
+		cli::Command::Watch(args) => {
+			watch = Some(args);
+			Vec::new()
+		},
+		cli::Command::Remove(args) => {
+			// host packages don't record what they depend on so there's nothing to cascade to
+			if host && (args.cascade || args.force) {
+				output::error("--cascade and --force can't be used with --host");
+				exit(1);
+			}
+			remove = true;
+			cascade = args.cascade;
+			force = args.force;
+			args.packages.names
+		},
+		cli::Command::Autoremove => {
+			autoremove = true;
+			Vec::new()
+		},
+		cli::Command::Clean(args) => {
+			let all = !args.sources && !args.builds && !args.dest && !args.archives;
+			clean = Some(CleanOptions {
+				sources: args.sources || all,
+				builds: args.builds || all,
+				dest: args.dest || all,
+				archives: args.archives
+			});
+			args.packages.names
+		},
+		cli::Command::New(args) => {
+			new_recipe = Some(args);
+			Vec::new()
+		},
+		cli::Command::Bump(args) => {
+			bump = Some(args);
+			Vec::new()
+		},
+		cli::Command::PatchRefresh(args) => {
+			patch_refresh = Some(args);
+			Vec::new()
+		},
+		cli::Command::Gc => {
+			gc = true;
+			Vec::new()
+		},
+		cli::Command::GenPatch(args) => {
+			gen_patch = true;
+			if !args.series.is_empty() {
+				gen_patch_series = true;
+				args.series
+			} else {
+				gen_patch_name = args.name.unwrap();
+				gen_patch_pattern = args.pattern.unwrap();
+				args.names
+			}
+		},
+		cli::Command::Report => {
+			report = true;
+			Vec::new()
+		},
+		cli::Command::Env(args) => {
+			print_env = true;
+			env_step = args.step;
+			args.packages.names
+		},
+		cli::Command::Shell(args) => {
+			open_shell = true;
+			run.sandbox = args.sandbox;
+			env_step = args.env.step;
+			args.env.packages.names
+		},
+		cli::Command::CheckRepro(args) => {
+			ops.push(Op::Prepare);
+			check_repro = true;
+			vary_path = args.vary_path;
+			vary_umask = args.vary_umask;
+			vary_time = args.vary_time;
+			run = args.run;
+			args.packages.names
+		},
+		cli::Command::Completions { shell } => {
+			cli::print_completions(shell);
+			return;
+		}
+	};
+
+	let RunArgs { dev, sandbox, shell_on_failure, keep_going } = run;
+
+	let (mut config, config_path) = load_config(config_path);
+
+	let templates = if let Some(path) = &config.general.templates_file {
+		load_templates(&config_path, path).templates
+	} else {
+		HashMap::new()
+	};
+
+	if sandbox {
+		config.general.sandbox = true;
+	}
+
+	if vary_time && which::which("faketime").is_err() {
+		output::error("--vary-time needs faketime in PATH");
+		exit(1);
+	}
+
+	if config.general.sandbox && which::which("bwrap").is_err() {
+		output::error("the sandbox needs bwrap in PATH");
+		exit(1);
+	}
+
+	if config.general.threads == 0 {
+		config.general.threads = std::thread::available_parallelism()
+			.map(|num| num.get())
+			.unwrap_or(1);
+	}
+
+	let state = State::new(
+		config,
+		Path::new(&config_path).parent().unwrap().to_path_buf(),
+		templates);
+
+	let meta_dir = state.config.general.meta_dir.clone();
+	let meta_dir = Path::new(&meta_dir);
+
+	let sysroot = Path::new(&state.config.general.sysroot);
+	let host_meta_dir = Path::new(&state.config.general.build_root).join("host_meta");
+	let host_prefix = state.config.general.host_prefix.as_ref().map(PathBuf::from);
+
+	if report {
+		timing::report(&state);
+		return;
+	}
+
+	if let Some(options) = clean {
+		clean::clean(&state, &names, host, &options);
+		return;
+	}
+
+	if gc {
+		clean::gc(&state);
+		return;
+	}
+
+	if let Some(args) = new_recipe {
+		scaffold::new_recipe(&state, &args.name, &args.url, args.template, host);
+		return;
+	}
+
+	if let Some(args) = bump {
+		bump::bump(&state, &args.name, &args.version, host);
+		return;
+	}
+
+	if let Some(args) = watch {
+		let invocation = watch::Invocation { config_path: &config_path, env: &env_args, run: args.run };
+		watch::watch(&state, &invocation, host, &args);
+		return;
+	}
+
+	if let Some(args) = patch_refresh {
+		if args.resume {
+			refresh::resume(&state, &args.name, host);
+		} else if args.abort {
+			refresh::abort(&state, &args.name, host);
+		} else if let Some(from) = &args.from {
+			refresh::start(&state, &args.name, from, host);
+		}
+		return;
+	}
+
+	if remove {
+		if host {
+			installed::remove_host(Path::new(&state.config.general.build_root), host_prefix.as_deref(), &names);
+		} else {
+			installed::remove(meta_dir, sysroot, &names, cascade, force);
+		}
+		return;
+	}
+
+	if autoremove {
+		installed::autoremove(meta_dir, sysroot);
+		return;
+	}
+
+	let abs_host_cc = which::which(&state.config.build.cc)
+		.expect("failed to find build cc in PATH");
+	let abs_host_cxx = which::which(&state.config.build.cxx)
+		.expect("failed to find build cxx in PATH");
+
+	let target_cc = state.config.target.cc.replace("@BUILDROOT@", &state.config.general.build_root);
+	let target_cxx = state.config.target.cc.replace("@BUILDROOT@", &state.config.general.build_root);
+
+	global_env.push(("CC".to_string(), target_cc));
+	global_env.push(("CXX".to_string(), target_cxx));
+	global_env.push(("QPKG_HOST_CC".to_string(), abs_host_cc.to_str().unwrap().to_string()));
+	global_env.push(("QPKG_HOST_CXX".to_string(), abs_host_cxx.to_str().unwrap().to_string()));
+	if !state.config.target.cflags.is_empty() {
+		global_env.push(("CFLAGS".to_string(), state.config.target.cflags.clone()));
+	}
+	if !state.config.target.cxxflags.is_empty() {
+		global_env.push(("CXXFLAGS".to_string(), state.config.target.cxxflags.clone()));
+	}
+	if !state.config.target.ldflags.is_empty() {
+		global_env.push(("LDFLAGS".to_string(), state.config.target.ldflags.clone()));
+	}
+	for (name, value) in &state.config.target.others {
+		global_env.push((name.clone(), value.clone()));
+	}
+	if !state.config.target.test_runner.is_empty() {
+		global_env.push(("QPKG_TEST_RUNNER".to_string(), test_runner(&state, &state.config.target.test_runner)));
+	}
+
+	let mut global_host_env = Vec::new();
+	global_host_env.push(("CC".to_string(), state.config.build.cc.clone()));
+	global_host_env.push(("CXX".to_string(), state.config.build.cxx.clone()));
+	if !state.config.build.cflags.is_empty() {
+		global_host_env.push(("CFLAGS".to_string(), state.config.build.cflags.clone()));
+	}
+	if !state.config.build.cxxflags.is_empty() {
+		global_host_env.push(("CXXFLAGS".to_string(), state.config.build.cxxflags.clone()));
+	}
+	if !state.config.build.ldflags.is_empty() {
+		global_host_env.push(("LDFLAGS".to_string(), state.config.build.ldflags.clone()));
+	}
+	if !state.config.build.test_runner.is_empty() {
+		global_host_env.push(("QPKG_TEST_RUNNER".to_string(), test_runner(&state, &state.config.build.test_runner)));
+	}
+
+	let mut force_prepare = false;
+	let mut force_configure = false;
+	let mut force_build = false;
+	let mut force_install = false;
+
+	let mut do_prepare = false;
+	let mut do_configure = false;
+	let mut do_build = false;
+	let mut do_install = false;
+	let mut do_sync = false;
+
+	if force {
+		for op in &ops {
+			match op {
+				Op::Prepare => force_prepare = true,
+				Op::Configure => force_configure = true,
+				Op::Build => force_build = true,
+				Op::Install => force_install = true,
+				Op::Sync => {}
+			}
+		}
+	}
+
+	for op in &ops {
+		match op {
+			Op::Prepare => do_prepare = true,
+			Op::Configure => do_configure = true,
+			Op::Build => do_build = true,
+			Op::Install => do_install = true,
+			Op::Sync => do_sync = true
+		}
 	}
 
 	if do_install {
@@ -737,7 +1382,8 @@ fn main() {
 		do_prepare = true;
 	}
 
-	let doc_path = Path::new(&state.config.general.doc_dir);
+	let build_root_dir = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
 
 	struct Entry {
 		name: String,
@@ -751,16 +1397,30 @@ fn main() {
 		.collect();
 
 	let mut existing_path = std::env::var("PATH").expect("no PATH set");
+	let mut reexported_dirs = Vec::new();
+	let user_shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
 
 	struct Package {
 		path: String,
-		aclocal: String
+		aclocal: String,
+		prefix: PathBuf
 	}
 
 	let mut packages = HashMap::new();
 	let mut host_packages = HashMap::new();
 
-	while let Some(entry) = stack.pop() {
+	let mut failures: Vec<Failure> = Vec::new();
+	let mut skipped: Vec<(String, bool)> = Vec::new();
+	let mut built = Vec::new();
+
+	let mut tests_passed = Vec::new();
+	let mut tests_failed: Vec<Failure> = Vec::new();
+	let mut untested = Vec::new();
+
+	let mut plan = Vec::new();
+	let mut binary_alternatives = HashMap::new();
+
+	'packages: while let Some(entry) = stack.pop() {
 		if entry.host {
 			if host_packages.contains_key(&entry.name) {
 				continue;
@@ -774,6 +1434,9 @@ fn main() {
 		let mut recipe = load_recipe(&state.config, &entry.name, entry.host);
 
 		if state.config.general.prefer_binaries && !recipe.general.binary_alternative.is_empty() {
+			binary_alternatives.insert(
+				(recipe.general.binary_alternative.clone(), entry.host),
+				entry.name);
 			stack.push(Entry {
 				name: recipe.general.binary_alternative,
 				processed: false,
@@ -785,14 +1448,19 @@ fn main() {
 
 		let mut package = Package {
 			path: String::new(),
-			aclocal: String::new()
+			aclocal: String::new(),
+			prefix: PathBuf::new()
 		};
 
 		if entry.host {
-			let path = std::path::absolute(Path::new(&state.config.general.build_root)
-				.join("host_pkgs")
-				.join(&entry.name))
-				.expect("failed to get absolute path for host package");
+			let path = match &host_prefix {
+				Some(host_prefix) => host_prefix.clone(),
+				None => std::path::absolute(Path::new(&state.config.general.build_root)
+					.join("host_pkgs")
+					.join(&entry.name))
+					.expect("failed to get absolute path for host package")
+			};
+			package.prefix = path.clone();
 			for dir in ["bin", "usr/bin", "usr/local/bin"] {
 				if !package.path.ends_with(':') {
 					package.path += ":";
@@ -809,6 +1477,7 @@ fn main() {
 					existing_path += ":";
 				}
 				existing_path += &package.path;
+				reexported_dirs.push(package.prefix.clone());
 			}
 
 			if recipe.general.exports_aclocal {
@@ -857,10 +1526,17 @@ fn main() {
 
 		let archives_dir = Path::new(&state.config.general.build_root)
 			.join("archives");
+		let pkg_meta_dir = if entry.host {
+			host_meta_dir.join(&entry.name)
+		} else {
+			meta_dir.join(&entry.name)
+		};
 
-		create_dir(&root_src_dir);
-		create_dir(&archives_dir);
-		create_dir(&dest_dir);
+		if !dry_run {
+			create_dir(&root_src_dir);
+			create_dir(&archives_dir);
+			create_dir(&dest_dir);
+		}
 
 		finalize_recipe(&mut recipe, &state, &root_src_dir, &dest_dir);
 
@@ -881,14 +1557,342 @@ fn main() {
 			packages.insert(recipe.general.name.clone(), package);
 		}
 
+		let local_src = state.local_source(&entry.name, entry.host);
+		if local_src.is_some() && recipe.general.workdir.is_empty() {
+			output::error(format_args!("{} needs a workdir to use a local source", display_name(&entry.name, entry.host)));
+			exit(1);
+		}
+		let source_changed = prepared_source_changed(&recipe, &archives_dir, &root_src_dir, local_src);
+		if recipe.general.build_in_source && recipe.general.copy_source_to_build {
+			output::error(format_args!("{} sets both build_in_source and copy_source_to_build", display_name(&entry.name, entry.host)));
+			exit(1);
+		}
+
+		// a named step that is run on its own needs the built-in step it comes after and the named
+		// steps in between, but not the other named steps
+		let (do_prepare, do_configure, do_build, do_install, chain) = match &run_step {
+			Some(step) if entry.user_specified => {
+				let (builtin, chain) = recipe.chain(step);
+				let Some(index) = build::BUILTIN_STEPS.iter().position(|name| *name == builtin) else {
+					let hook_of = step.strip_prefix("pre_")
+						.or_else(|| step.strip_prefix("post_"))
+						.filter(|builtin| build::BUILTIN_STEPS.contains(builtin));
+					if let Some(builtin) = hook_of {
+						output::error(format_args!("{} is a hook of {} and only runs with it, run the step {} instead", step, builtin, builtin));
+					} else {
+						output::error(format_args!("{} has no step {}", display_name(&entry.name, entry.host), step));
+					}
+					exit(1);
+				};
+				let chain: Vec<_> = chain.iter().map(|step| step.name.clone()).collect();
+				(true, index >= 1, index >= 2, index >= 3, Some(chain))
+			},
+			_ => (do_prepare, do_configure, do_build, do_install, None)
+		};
+		let named_requested = |name: &str| chain.as_ref().is_none_or(|chain| chain.iter().any(|step| step == name));
+		let named_forced = |name: &str| entry.user_specified && force && run_step.as_deref() == Some(name);
+
+		if dry_run {
+			let requested = |op: bool| !entry.user_specified || op;
+			let forced = |force: bool| entry.user_specified && force;
+			// a forced configure removes the whole build dir and the stamps in it
+			let wipes_build_dir = requested(do_configure) && forced(force_configure);
+			let stamp_missing = |stamp: &str| wipes_build_dir || !build_dir.join(stamp).exists();
+
+			let fetch = recipe.general.src.iter()
+				.filter(|_| local_src.is_none())
+				.filter(|src| src.contains(".git") || src.starts_with("http"))
+				.filter(|src| !source_path(&recipe, &archives_dir, src).exists())
+				.cloned()
+				.collect();
+
+			let mut steps = Vec::new();
+			let plan_named_steps = |steps: &mut Vec<String>, builtin: &str| {
+				for step in recipe.steps_after(builtin) {
+					if named_requested(&step.name) &&
+						(named_forced(&step.name) || wipes_build_dir || steps.contains(&step.after) || step_outdated(&root_src_dir, &build_dir, step)) {
+						steps.push(step.name.clone());
+					}
+				}
+			};
+
+			if requested(do_prepare) {
+				if forced(force_prepare) || source_changed || !root_src_dir.join("qpkg.prepared").exists() {
+					steps.push("prepare".to_string());
+				}
+				plan_named_steps(&mut steps, "prepare");
+			}
+			if requested(do_configure) {
+				if stamp_missing("qpkg.configured") {
+					steps.push("configure".to_string());
+				}
+				plan_named_steps(&mut steps, "configure");
+			}
+			let rebuild = local_src.is_some() || source_changed || source_modified(&root_src_dir.join(&recipe.general.workdir), &build_dir);
+			if requested(do_build) {
+				if forced(force_build) || rebuild || stamp_missing("qpkg.built") {
+					steps.push("build".to_string());
+				}
+				plan_named_steps(&mut steps, "build");
+			}
+			if test && entry.user_specified && !recipe.check.args.is_empty() {
+				steps.push("check".to_string());
+			}
+			if requested(do_install) {
+				if forced(force_install) || rebuild || stamp_missing("qpkg.installed") {
+					steps.push("install".to_string());
+				}
+				plan_named_steps(&mut steps, "install");
+			}
+			let plans_install = steps.iter().any(|step| step == "install");
+
+			let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
+			let already_synced = !entry.user_specified && !installed.trim().is_empty();
+
+			let should_sync = (!entry.host || host_prefix.is_some()) && should_sync(
+				entry.host,
+				entry.user_specified,
+				already_synced,
+				plans_install,
+				local_src.is_some(),
+				do_sync);
+
+			let sync = if should_sync {
+				let files: Vec<_> = if dest_dir.exists() {
+					dest_files(&state, &dest_dir).into_iter()
+						.map(|path| path.to_str().unwrap().to_string())
+						.filter(|name| !name.is_empty())
+						.collect()
+				} else {
+					Vec::new()
+				};
+
+				Some(SyncPlan {
+					added: files.iter()
+						.filter(|name| !installed.lines().any(|line| line == name.as_str()))
+						.cloned()
+						.collect(),
+					removed: installed.lines()
+						.map(|line| line.trim())
+						.filter(|line| !line.is_empty() && !files.iter().any(|name| name == line))
+						.map(String::from)
+						.collect(),
+					after_install: plans_install
+				})
+			} else {
+				None
+			};
+
+			plan.push(PackagePlan {
+				binary_alternative_of: binary_alternatives.get(&(entry.name.clone(), entry.host)).cloned(),
+				package: entry.name,
+				host: entry.host,
+				fetch,
+				steps,
+				sync
+			});
+			continue;
+		}
+
+		let broken_dep = recipe.general.depends.iter().map(|dep| (dep, false))
+			.chain(recipe.general.host_depends.iter().map(|dep| (dep, true)))
+			.find(|(dep, host)| {
+				// a binary alternative that failed stands for the package it replaces
+				let is_dep = |name: &String, failed_host: bool| failed_host == *host && (name == *dep ||
+					binary_alternatives.get(&(name.clone(), failed_host)).is_some_and(|original| original == *dep));
+				failures.iter().any(|failure| is_dep(&failure.name, failure.host)) ||
+					skipped.iter().any(|(name, skipped_host)| is_dep(name, *skipped_host))
+			});
+		if let Some((dep, dep_host)) = broken_dep {
+			output::info(format_args!(
+				"skipping {} because {} failed",
+				display_name(&entry.name, entry.host),
+				display_name(dep, dep_host)));
+			skipped.push((entry.name, entry.host));
+			continue;
+		}
+
 		let work_dir = std::path::absolute(root_src_dir.join(&recipe.general.workdir))
 			.expect("failed to get absolute srcdir");
+		let repro_dir = build_root_dir.join("repro").join(&entry.name);
+
+		let mut host_deps_path = String::new();
+		let mut aclocal = String::new();
+		let mut host_deps_dirs = Vec::new();
+		for name in &recipe.general.host_depends {
+			let pkg = if let Some(pkg) = host_packages.get(name) {
+				pkg
+			} else {
+				panic!("internal error: host dependency {} missing, this is a qpkg bug!", name);
+			};
+			host_deps_path += &pkg.path;
+			aclocal += &pkg.aclocal;
+			host_deps_dirs.push(pkg.prefix.clone());
+		}
+
+		let sandbox = if state.config.general.sandbox {
+			let mut writable = Vec::new();
+			for dir in [&root_src_dir, &build_dir, &dest_dir] {
+				writable.push(std::path::absolute(dir).expect("failed to get absolute path"));
+			}
+			// git sources are cloned outside of the source dir and symlinked into it
+			for src in recipe.general.src.iter().filter(|src| src.contains(".git")) {
+				let path = source_path(&recipe, &archives_dir, src);
+				writable.push(std::path::absolute(path).expect("failed to get absolute path"));
+			}
+			if let Some(local_src) = local_src {
+				writable.push(local_src.to_path_buf());
+			}
+			if !recipe.general.src_unpack_dir.is_empty() {
+				create_dir(Path::new(&recipe.general.src_unpack_dir));
+				writable.push(std::path::absolute(&recipe.general.src_unpack_dir)
+					.expect("failed to get absolute path"));
+			}
+			if check_repro && entry.user_specified {
+				create_dir(&repro_dir);
+				writable.push(repro_dir.clone());
+			}
+
+			// host packages that reexport their PATH are used by everything built after them
+			let mut readonly = host_deps_dirs;
+			readonly.extend(reexported_dirs.iter().cloned());
+			readonly.push(std::path::absolute(sysroot).expect("failed to make sysroot absolute"));
+
+			Some(Sandbox { writable, readonly })
+		} else {
+			None
+		};
+
+		let new_command = |program: &str, cwd: &Path, network: bool| match &sandbox {
+			Some(sandbox) => sandbox.command(Path::new(program), cwd, network),
+			None => Command::new(program)
+		};
+
+		// shell commands go through the shell of the recipe or config, an argv is run directly
+		let shell = recipe.general.shell.as_deref().unwrap_or(&state.config.general.shell);
+		let step_command = |command: &build::Command, cwd: &Path, network: bool, wrapper: &[&str], env: &[(String, String)]| {
+			let argv: Vec<&str> = match command {
+				build::Command::Shell(_) => vec![shell, "-c"],
+				build::Command::Exec { argv } => argv.iter().map(String::as_str).collect()
+			};
+			let argv: Vec<_> = wrapper.iter().chain(&argv).collect();
+
+			// looked up in the step's PATH here so that a missing program fails the step, the sandbox
+			// also only sees some host dirs
+			let path = env.iter().find(|(name, _)| name == "PATH").map(|(_, value)| value);
+			let program = which::which_in(argv[0], path, cwd)
+				.map_err(|_| format!("failed to find {} in PATH", argv[0]))?;
+			let mut cmd = match &sandbox {
+				Some(sandbox) => sandbox.command(&program, cwd, network),
+				None => {
+					let mut cmd = Command::new(&program);
+					std::os::unix::process::CommandExt::arg0(&mut cmd, argv[0]);
+					cmd
+				}
+			};
+			cmd.args(&argv[1..]);
+			if let build::Command::Shell(parts) = command {
+				cmd.arg(parts.join(" "));
+			}
+			cmd.current_dir(cwd).env_clear().envs(env.iter().cloned());
+			Ok::<_, String>((cmd, argv[0].to_string()))
+		};
+
+		let global_envs = if entry.host {
+			&global_host_env
+		} else {
+			&global_env
+		};
+
+		let epoch_path = root_src_dir.join("qpkg.epoch");
+		let read_epoch = || match read_to_string(&epoch_path) {
+			Ok(epoch) => epoch.trim().parse().ok(),
+			Err(_) => reproducible::source_date_epoch(&recipe, &archives_dir, &root_src_dir)
+		};
+
+		let package_env = |epoch: Option<u64>| {
+			let mut env = global_envs.clone();
+			if state.config.general.reproducible {
+				if let Some(epoch) = epoch {
+					set_env(&mut env, "SOURCE_DATE_EPOCH", &epoch.to_string());
+				}
+				set_env(&mut env, "TZ", "UTC");
+
+				// the more specific map goes last as it takes precedence, flags that aren't set stay unset
+				// so that configure scripts still use their defaults
+				let prefix_maps = format!(
+					"-ffile-prefix-map={}=/usr/src -ffile-prefix-map={}=/usr/src/{}-{}",
+					build_root_dir.display(),
+					work_dir.display(),
+					recipe.general.name,
+					recipe.general.version);
+				for (name, flags) in &mut env {
+					if name == "CFLAGS" || name == "CXXFLAGS" {
+						*flags = format!("{} {}", flags, prefix_maps);
+					}
+				}
+			}
+			env
+		};
+		let real_path = host_deps_path.clone() + ":" + &existing_path;
+		let sysroot_dir = std::path::absolute(&state.config.general.sysroot)
+			.expect("failed to make sysroot absolute");
+
+		if print_env || open_shell {
+			if entry.user_specified {
+				let Some(step) = recipe.step(&env_step) else {
+					output::error(format_args!("{} has no step {}", display_name(&entry.name, entry.host), env_step));
+					exit(1);
+				};
+				let prepare = recipe.in_prepare(&env_step);
+				let sysroot_dir = if prepare {
+					None
+				} else {
+					Some(sysroot_dir.as_path())
+				};
+
+				let env = package_env(read_epoch());
+				let env = step_env(&state, entry.host, &env, step, &real_path, &aclocal, sysroot_dir);
+
+				if open_shell {
+					let (cwd, network) = if prepare {
+						(&work_dir, true)
+					} else if recipe.general.build_in_source {
+						(&work_dir, false)
+					} else {
+						(&build_dir, false)
+					};
+					create_dir(cwd);
+					start_shell(new_command(&user_shell, cwd, network), cwd, &env);
+				} else {
+					for (name, value) in env {
+						println!("{}={}", name, value);
+					}
+				}
+			}
+			continue;
+		}
+
 		if gen_patch {
 			if !work_dir.join(".git").exists() {
-				eprintln!("error: gen-patch needs a git repository to work");
+				output::error("gen-patch needs a git repository to work");
 				exit(1);
 			}
 
+			if gen_patch_series {
+				if recipe.general.patches.is_some() {
+					output::error(format_args!("the patches of {} are listed in its recipe, remove the list to use a series file", entry.name));
+					exit(1);
+				}
+
+				let target = if entry.host { "host" } else { &state.config.general.target };
+				let dir = patches::dir(recipes_dir(&state, entry.host), &entry.name);
+				let all = patches::all(&recipe, &dir);
+				let applied = patches::series(&recipe, &dir, target);
+				git::export_series(&work_dir, &dir, &all, &applied);
+				continue;
+			}
+
 			let output = Command::new("git")
 				.arg("diff")
 				.arg(&gen_patch_pattern)
@@ -897,7 +1901,7 @@ fn main() {
 				.expect("failed to spawn git");
 
 			if !output.status.success() {
-				eprintln!("error: git failed with status {}", output.status);
+				output::error(format_args!("git failed with status {}", output.status));
 				exit(1);
 			}
 
@@ -913,7 +1917,23 @@ fn main() {
 			match write(&patch_file, output.stdout) {
 				Ok(_) => {},
 				Err(e) => {
-					eprintln!("error: failed to write {}: {}", patch_file.display(), e);
+					output::error(format_args!("failed to write {}: {}", patch_file.display(), e));
+					exit(1);
+				}
+			}
+
+			// a series file lists every patch that is applied so the new one has to be added to it
+			let series_path = patches_dir.join("series");
+			let patch_name = format!("{}.patch", gen_patch_name);
+			if let Ok(series) = read_to_string(&series_path) &&
+				!series.lines().any(|line| line.split_whitespace().next() == Some(&patch_name)) {
+				let series = if series.is_empty() || series.ends_with('\n') {
+					format!("{}{}\n", series, patch_name)
+				} else {
+					format!("{}\n{}\n", series, patch_name)
+				};
+				if let Err(e) = write(&series_path, series) {
+					output::error(format_args!("failed to write {}: {}", series_path.display(), e));
 					exit(1);
 				}
 			}
@@ -921,97 +1941,91 @@ fn main() {
 			continue;
 		}
 
-		for src in &recipe.general.src {
-			let name = source_to_name(src);
+		let package_start = Instant::now();
+		output::emit(Event::PackageStarted { package: &entry.name, host: entry.host });
 
-			let path = if !recipe.general.src_unpack_dir.is_empty() {
-				Path::new(&recipe.general.src_unpack_dir).join(name)
-			} else {
-				archives_dir.join(name)
-			};
+		let fetch_timer = Timer::start();
+		let mut fetched = false;
+		let fetch_log_path = logs::log_path(&build_root_dir, &entry.name, entry.host, "fetch");
+		let mut fetch_log = None;
+		// archives that were checked after being downloaded, the others are checked before unpacking
+		let mut verified = Vec::new();
+
+		// local sources are used as they are
+		let sources: &[String] = if local_src.is_some() { &[] } else { &recipe.general.src };
+
+		for src in sources {
+			let name = source_to_name(src);
+			let path = source_path(&recipe, &archives_dir, src);
 
 			if !path.exists() {
-				if let Some(pos) = src.find(".git") {
-					let mut full = false;
-					let mut branch = "";
-
-					if pos + 4 != src.len() && &src[pos + 4..pos + 5] == ":" {
-						let opts = &src[pos + 5..];
-						if let Some(pos) = opts.find(",full") {
-							branch = &opts[0..pos];
-							full = true;
-						} else {
-							branch = opts;
-						}
-					}
+				let Some((mut cmd, method, url)) = fetch_command(src, &path, recipe.general.recurse_submodules) else {
+					continue;
+				};
 
-					println!("info: fetching {} using git", &src[0..pos]);
-
-					let branch_args = ["-b", branch];
-
-					let cmd = Command::new("git")
-						.arg("clone")
-						.arg(&src[0..pos])
-						.args(if !full {
-							["--depth=1"].as_slice()
-						} else {
-							[].as_slice()
-						})
-						.args(if !branch.is_empty() {
-							branch_args.as_slice()
-						} else {
-							[].as_slice()
-						})
-						.args(if recipe.general.recurse_submodules {
-							["--recurse-submodules"].as_slice()
-						} else {
-							[].as_slice()
-						})
-						.arg(path.to_str().unwrap())
-						.spawn().expect("failed to spawn git")
-						.wait().expect("git failed");
-					if !cmd.success() {
-						eprintln!("error: git failed with {}", cmd);
-						exit(1);
-					}
-				} else if src.starts_with("http") {
-					println!("info: fetching {} using wget", src);
-
-					let cmd = Command::new("wget")
-						.arg(src)
-						.args(["-O", path.to_str().unwrap()])
-						.spawn().expect("failed to spawn wget")
-						.wait().expect("wget failed");
-					if !cmd.success() {
-						eprintln!("error: wget failed with {}", cmd);
-						exit(1);
+				let fetch_start = Instant::now();
+				output::emit(Event::FetchStarted {
+					package: &entry.name,
+					source: url,
+					method
+				});
+
+				let status = logs::run(&mut cmd, fetch_log.get_or_insert_with(|| logs::open_log(&fetch_log_path)));
+				if let Some(message) = logs::failure(&status, method) {
+					output::emit(Event::Error {
+						package: Some(&entry.name),
+						step: Some("fetch"),
+						command: Some(if method == "git" { "git clone" } else { method }),
+						message
+					});
+					// don't leave a partial download behind that would be used by the next run
+					if method == "wget" {
+						remove_file(&path);
 					}
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "fetch", &fetch_log_path);
+					continue 'packages;
+				}
+
+				if let Some(message) = checksum_mismatch(&recipe, name, &path) {
+					output::emit(Event::Error { package: Some(&entry.name), step: Some("fetch"), command: None, message });
+					remove_file(&path);
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "fetch", &fetch_log_path);
+					continue 'packages;
 				}
+				verified.push(src);
+
+				output::emit(Event::FetchFinished {
+					package: &entry.name,
+					source: src,
+					bytes: path.metadata().map(|meta| meta.len()).unwrap_or(0),
+					duration: fetch_start.elapsed().as_secs_f64()
+				});
+				fetched = true;
 			}
 		}
 
-		let mut host_deps_path = String::new();
-		let mut aclocal = String::new();
-		for name in &recipe.general.host_depends {
-			let pkg = if let Some(pkg) = host_packages.get(name) {
-				pkg
-			} else {
-				panic!("internal error: host dependency {} missing, this is a qpkg bug!", name);
-			};
-			host_deps_path += &pkg.path;
-			aclocal += &pkg.aclocal;
+		if fetched {
+			timing::record(
+				Path::new(&state.config.general.build_root),
+				&entry.name,
+				entry.host,
+				fetch_timer.finish("fetch"));
 		}
 
 		if !entry.user_specified || do_prepare {
 			let prepared_path = root_src_dir.join("qpkg.prepared");
 
+			if source_changed {
+				remove_file(&prepared_path);
+			}
+
 			if entry.user_specified && force_prepare {
-				println!("info: forcing prepare for {}", entry.name);
+				output::info(format_args!("forcing prepare for {}", entry.name));
 				match std::fs::remove_file(&prepared_path) {
 					Ok(_) => {},
 					Err(e) => {
 						if e.kind() != std::io::ErrorKind::NotFound {
-							eprintln!("error: failed to remove {}: {}", prepared_path.display(), e);
+							output::error(format_args!("failed to remove {}: {}", prepared_path.display(), e));
 							exit(1);
 						}
 					}
@@ -1019,43 +2033,59 @@ fn main() {
 			}
 
 			if !prepared_path.exists() {
-				println!("info: preparing source for {}", entry.name);
+				let timer = Timer::start();
+				output::emit(Event::StepStarted { package: &entry.name, step: "prepare" });
 
 				std::fs::remove_dir_all(&root_src_dir).expect("failed to remove srcdir");
 				create_dir_all(&root_src_dir).expect("failed to create srcdir");
 
-				if !recipe.general.no_auto_unpack {
+				let prepare_log_path = logs::log_path(&build_root_dir, &entry.name, entry.host, "prepare");
+				let prepare_log = logs::open_log(&prepare_log_path);
+
+				if let Some(local_src) = local_src {
+					create_dir(work_dir.parent().unwrap());
+					if let Err(e) = std::os::unix::fs::symlink(local_src, &work_dir) {
+						output::error(format_args!("failed to symlink {} -> {}: {}", local_src.display(), work_dir.display(), e));
+						exit(1);
+					}
+				} else if !recipe.general.no_auto_unpack {
 					for src in &recipe.general.src {
-						let name = source_to_name(src);
-
-						let path = if !recipe.general.src_unpack_dir.is_empty() {
-							Path::new(&recipe.general.src_unpack_dir).to_owned().join(name)
-						} else {
-							archives_dir.join(name)
-						}.canonicalize().expect("failed to canonicalize src path");
-
-						if src.ends_with(".tar.xz") ||
-							src.ends_with(".tar.gz") ||
-							src.ends_with(".tar.bz2") ||
-							src.ends_with(".tar.zst") {
-							let cmd = Command::new("tar")
-								.arg("-xf")
+						let path = source_path(&recipe, &archives_dir, src)
+							.canonicalize()
+							.expect("failed to canonicalize src path");
+
+						if is_tarball(src) {
+							if !verified.contains(&src) &&
+								let Some(message) = checksum_mismatch(&recipe, source_to_name(src), &path) {
+								output::emit(Event::Error { package: Some(&entry.name), step: Some("prepare"), command: None, message });
+								remove_file(&path);
+								package_failed(&mut failures, keep_going, &entry.name, entry.host, "prepare", &prepare_log_path);
+								continue 'packages;
+							}
+
+							let mut tar = Command::new("tar");
+							tar.arg("-xf")
 								.arg(path.to_str().unwrap())
-								.current_dir(&root_src_dir)
-								.spawn().expect("failed to spawn tar")
-								.wait().expect("tar failed");
-							if !cmd.success() {
-								eprintln!("error: tar failed with {}", cmd);
-								exit(1);
+								.current_dir(&root_src_dir);
+							let status = logs::run(&mut tar, &prepare_log);
+							if let Some(message) = logs::failure(&status, "tar") {
+								output::emit(Event::Error {
+									package: Some(&entry.name),
+									step: Some("prepare"),
+									command: Some("tar -xf"),
+									message
+								});
+								package_failed(&mut failures, keep_going, &entry.name, entry.host, "prepare", &prepare_log_path);
+								continue 'packages;
 							}
 						} else if src.contains(".git") {
 							if let Err(err) = std::os::unix::fs::symlink(&path, &work_dir) {
 								if err.kind() != std::io::ErrorKind::AlreadyExists {
-									eprintln!(
-										"error: failed to symlink {} -> {}: {}",
+									output::error(format_args!(
+										"failed to symlink {} -> {}: {}",
 										path.display(),
 										work_dir.display(),
-										err);
+										err));
 									exit(1);
 								}
 							}
@@ -1065,37 +2095,55 @@ fn main() {
 
 				create_dir_all(&work_dir).ok();
 
+				// computed before patching so that patched files don't affect it
+				let epoch = match local_src {
+					Some(local_src) => recipe.general.source_date_epoch.or_else(|| reproducible::git_commit_time(local_src)),
+					None => reproducible::source_date_epoch(&recipe, &archives_dir, &root_src_dir)
+				};
+				if let Some(epoch) = epoch {
+					match write(&epoch_path, epoch.to_string()) {
+						Ok(_) => {},
+						Err(e) => {
+							output::error(format_args!("failed to write {}: {}", epoch_path.display(), e));
+							exit(1);
+						}
+					}
+				}
+
 				let recipes_dir = if entry.host {
 					Path::new(&state.config.general.host_recipes_dir)
 				} else {
 					Path::new(&state.config.general.recipes_dir)
 				};
 
-				if dev && !work_dir.join(".git").exists() {
+				// patches are only committed to repositories made here, git sources are clones in archives/
+				let commit_patches = dev && local_src.is_none() && !work_dir.join(".git").exists();
+				if commit_patches {
 					let exec_git_cmd = |cmd: &[&str], msg: &str| {
 						let status = match Command::new("git")
 							.args(cmd)
 							.current_dir(&work_dir)
+							.stdout(output::child_stdout())
 							.spawn() {
 							Ok(mut child) => {
 								match child.wait() {
 									Ok(res) => res,
 									Err(e) => {
-										eprintln!("{}: {}", msg, e);
+										output::error(format_args!("{}: {}", msg, e));
 										std::fs::remove_dir_all(work_dir.join(".git")).ok();
 										exit(1);
 									}
 								}
 							},
 							Err(e) => {
-								eprintln!("error: failed to spawn git: {}", e);
+								output::error(format_args!("failed to spawn git: {}", e));
 								std::fs::remove_dir_all(work_dir.join(".git")).ok();
 								exit(1);
 							}
 						};
 
 						if !status.success() {
-							eprintln!("{}: {}", msg, status);
+							output::error(format_args!("{}: {}", msg, status));
 							std::fs::remove_dir_all(work_dir.join(".git")).ok();
 							exit(1);
 						}
@@ -1103,254 +2151,439 @@ fn main() {
 
 					exec_git_cmd(
 						&["init", "-b", "main"],
-						"error: failed to initialize git repository");
+						"failed to initialize git repository");
 					exec_git_cmd(
 						&["add", "."],
-						"error: failed to add files to git");
+						"failed to add files to git");
 					exec_git_cmd(
 						&["commit", "-m", "\"Initial commit\""],
-						"error: failed to make git commit");
+						"failed to make git commit");
+					exec_git_cmd(
+						&["tag", git::BASE_TAG],
+						"failed to tag the initial commit");
 				}
 
-				let patches_dir = std::path::absolute(recipes_dir
-					.join(&entry.name)
-					.join("patches"))
-					.expect("failed to get absolute patches dir");
-				if !recipe.general.no_auto_patch && patches_dir.exists() {
-					for file in WalkDir::new(&patches_dir) {
-						let file = file.unwrap();
-						let path = file.path();
-
-						if let Some(ext) = path.extension() {
-							let ext = ext.to_str().unwrap();
-							if matches!(ext, "patch" | "diff") {
-								println!("info: applying patch {}", file.file_name().to_str().unwrap());
-
-								let cmd = Command::new("patch")
-									.arg("-Np1")
-									.args(["-i", path.to_str().unwrap()])
-									.current_dir(&work_dir)
-									.spawn().expect("failed to spawn patch")
-									.wait().expect("patch failed");
-								if !cmd.success() {
-									eprintln!("error: patch failed with {}", cmd);
-									exit(1);
-								}
-							}
+				if !recipe.general.no_auto_patch && local_src.is_none() {
+					let target = if entry.host { "host" } else { &state.config.general.target };
+					for patch in patches::series(&recipe, &patches::dir(recipes_dir, &entry.name), target) {
+						let applied = patches::apply(&patch, &work_dir, &prepare_log, false);
+						if !applied.status.success() {
+							output::emit(Event::Error {
+								package: Some(&entry.name),
+								step: Some("prepare"),
+								command: Some("patch"),
+								message: format!("patch {} failed with {}", patch.name(), applied.status)
+							});
+							package_failed(&mut failures, keep_going, &entry.name, entry.host, "prepare", &prepare_log_path);
+							continue 'packages;
+						}
+
+						for warning in applied.warnings {
+							output::emit(Event::Warning {
+								package: Some(&entry.name),
+								message: format!("patch {}: {}", patch.name(), warning)
+							});
+						}
+						if commit_patches {
+							git::commit_patch(&work_dir, &patch);
 						}
+						output::emit(Event::PatchApplied { package: &entry.name, patch: patch.name() });
 					}
 				}
 
-				for args in &recipe.prepare.args {
-					let value = args.join(" ");
+				// the hooks run after the source was unpacked and patched
+				for step in recipe.builtin("prepare") {
+					let env = step_env(&state, entry.host, &package_env(epoch), step, &real_path, &aclocal, None);
 
-					let global_envs = if entry.host {
-						&global_host_env
-					} else {
-						&global_env
-					}.iter().map(|(name, value)| (name.as_str(), value.as_str()));
-
-					let env: Vec<_> = recipe.prepare.env
-						.iter()
-						.map(|map| map.iter().next().unwrap())
-						.collect();
-
-					let real_path = host_deps_path.clone() + ":" + &existing_path;
-
-					let cmd = Command::new("/bin/sh")
-						.arg("-c")
-						.arg(&value)
-						.current_dir(&work_dir)
-						.env("LC_ALL", "C")
-						.envs(env.iter().map(|(name, value)| (name.as_str(), value.as_str())))
-						.envs(global_envs)
-						.env("PATH", &real_path)
-						.env("ACLOCAL_PATH", &aclocal)
-						.spawn().expect("failed to spawn sh")
-						.wait().expect("sh failed");
-					if !cmd.success() {
-						eprintln!("error: command {} failed with status {}", value, cmd);
-						exit(1);
+					for command in &step.args {
+						let value = command.display();
+
+						let status = step_command(command, &work_dir, true, &[], &env)
+							.and_then(|(mut cmd, program)| logs::run(&mut cmd, &prepare_log)
+								.map_err(|e| format!("failed to run {}: {}", program, e)));
+						let message = match status {
+							Ok(status) if status.success() => None,
+							Ok(status) => Some(format!("command {} failed with status {}", value, status)),
+							Err(e) => Some(e)
+						};
+						if let Some(message) = message {
+							output::emit(Event::Error {
+								package: Some(&entry.name),
+								step: Some("prepare"),
+								command: Some(&value),
+								message
+							});
+							if shell_on_failure {
+								start_shell(new_command(&user_shell, &work_dir, true), &work_dir, &env);
+							}
+							package_failed(&mut failures, keep_going, &entry.name, entry.host, "prepare", &prepare_log_path);
+							continue 'packages;
+						}
 					}
 				}
 
 				touch_file(prepared_path);
+				finish_step(&state, &entry.name, entry.host, "prepare", &timer);
 			}
 		}
 
-		let execute_step = |step: &Step| {
-			create_dir_all(&build_dir).expect("failed to create build dir");
+		let steps_env = package_env(read_epoch());
 
-			let env: Vec<_> = step.env
-				.iter()
-				.map(|map| map.iter().next().unwrap())
-				.collect();
+		// wrapper is a command that the commands are run through, e.g. faketime
+		let step_log_path = |step: &str| logs::log_path(&build_root_dir, &entry.name, entry.host, step);
+
+		let run_commands = |step: &Step, step_name: &str, build_dir: &Path, network: bool, wrapper: &[&str], log: &File| {
+			create_dir_all(build_dir).expect("failed to create build dir");
+
+			let env = step_env(&state, entry.host, &steps_env, step, &real_path, &aclocal, Some(&sysroot_dir));
+
+			for command in &step.args {
+				let value = command.display();
+
+				let status = step_command(command, build_dir, network, wrapper, &env)
+					.and_then(|(mut cmd, program)| logs::run(&mut cmd, log)
+						.map_err(|e| format!("failed to run {}: {}", program, e)));
+				let message = match status {
+					Ok(status) if status.success() => None,
+					Ok(status) => Some(format!("command {} failed with status {}", value, status)),
+					Err(e) => Some(e)
+				};
+				if let Some(message) = message {
+					output::emit(Event::Error {
+						package: Some(&entry.name),
+						step: Some(step_name),
+						command: Some(&value),
+						message
+					});
+					if shell_on_failure {
+						start_shell(new_command(&user_shell, build_dir, network), build_dir, &env);
+					}
+					return false;
+				}
+			}
 
-			let sysroot_dir = std::path::absolute(&state.config.general.sysroot)
-				.expect("failed to make sysroot absolute");
+			true
+		};
+
+		// the stamps stay in the build dir even when the steps run in the source
+		let step_dir = if recipe.general.build_in_source {
+			&work_dir
+		} else {
+			&build_dir
+		};
+
+		// the hooks of a built-in step are run as part of it
+		let execute_step = |steps: &[&Step], step_name: &str| {
+			let timer = Timer::start();
+			output::emit(Event::StepStarted { package: &entry.name, step: step_name });
+
+			// only the files that changed since the last step are copied again
+			if recipe.general.copy_source_to_build {
+				let changed = tree::sync(&work_dir, &build_dir);
+				if changed != 0 {
+					output::info(format_args!("copied {} changed files of {} to {}", changed, entry.name, build_dir.display()));
+				}
+			}
+
+			let (dir, network) = if recipe.in_prepare(step_name) {
+				(work_dir.as_path(), true)
+			} else {
+				(step_dir.as_path(), false)
+			};
+			let log = logs::open_log(&step_log_path(step_name));
+			if !steps.iter().all(|step| run_commands(step, step_name, dir, network, &[], &log)) {
+				return false;
+			}
+
+			finish_step(&state, &entry.name, entry.host, step_name, &timer);
+			true
+		};
+
+		// runs the named steps that come after a built-in step and returns the one that failed
+		let execute_named_steps = |builtin: &str| {
+			for step in recipe.steps_after(builtin) {
+				if !named_requested(&step.name) {
+					continue;
+				}
+
+				let stamp = step_stamp(&root_src_dir, &build_dir, &step.name);
+				if named_forced(&step.name) {
+					output::info(format_args!("forcing {} for {}", step.name, entry.name));
+					remove_file(&stamp);
+				}
+				if step_outdated(&root_src_dir, &build_dir, step) {
+					if !execute_step(&[&step.step], &step.name) {
+						return Err(step.name.clone());
+					}
+					touch_file(&stamp);
+				}
+			}
+			Ok(())
+		};
+
+		if check_repro && entry.user_specified {
+			let repro_log_path = step_log_path("check-repro");
+			let repro_log = logs::open_log(&repro_log_path);
+
+			// both variants are built at the same path and moved aside afterwards unless the path is
+			// one of the things that should vary
+			let variant_dirs = [repro_dir.join("a"), repro_dir.join("b")];
+			let build_dirs = [
+				repro_dir.join("work"),
+				if vary_path {
+					variant_dirs[1].clone()
+				} else {
+					repro_dir.join("work")
+				}
+			];
+
+			for (i, (variant_dir, build_path)) in variant_dirs.iter().zip(&build_dirs).enumerate() {
+				let second = i == 1;
+				output::info(format_args!("building {} in {}", entry.name, build_path.display()));
+
+				for dir in [variant_dir, build_path] {
+					if dir.exists() {
+						std::fs::remove_dir_all(dir).expect("failed to remove repro build dir");
+					}
+				}
 
-			for args in &step.args {
-				let value = args.join(" ");
+				let variant_dest_dir = build_path.join("pkgs").join(&entry.name);
+				create_dir(&variant_dest_dir);
 
-				let global_envs = if entry.host {
-					&global_host_env
+				let mut variant_recipe = load_recipe(&state.config, &entry.name, entry.host);
+				finalize_recipe(&mut variant_recipe, &state, &root_src_dir, &variant_dest_dir);
+
+				let old_umask = if vary_umask {
+					Some(unsafe { libc::umask(if second { 0o077 } else { 0o022 }) })
+				} else {
+					None
+				};
+				let wrapper: &[&str] = if second && vary_time {
+					&["faketime", "-f", "+397d"]
 				} else {
-					&global_env
-				}.iter().map(|(name, value)| (name.as_str(), value.as_str()));
-
-				let real_path = host_deps_path.clone() + ":" + &existing_path;
-
-				let cmd = Command::new("/bin/sh")
-					.arg("-c")
-					.arg(&value)
-					.current_dir(&build_dir)
-					.env("LC_ALL", "C")
-					.envs(env.iter().map(|(name, value)| (name.as_str(), value.as_str())))
-					.envs(global_envs)
-					.env("QPKG_SYSROOT_DIR", sysroot_dir.to_str().unwrap())
-					.env("PATH", &real_path)
-					.env("ACLOCAL_PATH", &aclocal)
-					.spawn().expect("failed to spawn sh")
-					.wait().expect("sh failed");
-				if !cmd.success() {
-					eprintln!("error: command {} failed with status {}", value, cmd);
+					&[]
+				};
+
+				// the real source isn't touched by the repro builds
+				if recipe.general.build_in_source || recipe.general.copy_source_to_build {
+					tree::sync(&work_dir, &build_path.join("build"));
+				}
+
+				let success = variant_recipe.build_order()
+					.into_iter()
+					.all(|(step_name, step)| {
+						run_commands(step, &step_name, &build_path.join("build"), false, wrapper, &repro_log)
+					});
+
+				if let Some(umask) = old_umask {
+					unsafe { libc::umask(umask) };
+				}
+
+				if !success {
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "check-repro", &repro_log_path);
+					continue 'packages;
+				}
+
+				if build_path != variant_dir && let Err(e) = std::fs::rename(build_path, variant_dir) {
+					output::error(format_args!("failed to move {} to {}: {}", build_path.display(), variant_dir.display(), e));
 					exit(1);
 				}
 			}
-		};
+
+			let same = reproducible::compare_trees(
+				&variant_dirs[0].join("pkgs").join(&entry.name),
+				&variant_dirs[1].join("pkgs").join(&entry.name));
+			output::emit(Event::ReproChecked {
+				package: &entry.name,
+				reproducible: same,
+				builds: repro_dir.display().to_string()
+			});
+			if !same {
+				package_failed(&mut failures, keep_going, &entry.name, entry.host, "check-repro", &repro_log_path);
+				continue;
+			}
+
+			built.push((entry.name, entry.host));
+			continue;
+		}
+
+		let mut installed_now = false;
+
+		// local sources can change at any time so they're always built and installed again, as is
+		// a package whose source was switched to or from a local one
+		if local_src.is_some() || source_changed {
+			remove_file(build_dir.join("qpkg.built"));
+			remove_file(build_dir.join("qpkg.installed"));
+		} else if source_modified(&work_dir, &build_dir) {
+			output::info(format_args!("the source of {} changed since it was built", display_name(&entry.name, entry.host)));
+			remove_file(build_dir.join("qpkg.built"));
+			remove_file(build_dir.join("qpkg.installed"));
+		}
+
+		if (!entry.user_specified || do_prepare) && let Err(step) = execute_named_steps("prepare") {
+			package_failed(&mut failures, keep_going, &entry.name, entry.host, &step, &step_log_path(&step));
+			continue;
+		}
 
 		if !entry.user_specified || do_configure {
 			if entry.user_specified && force_configure {
-				println!("info: forcing configure for {}", entry.name);
+				output::info(format_args!("forcing configure for {}", entry.name));
 				std::fs::remove_dir_all(&build_dir).expect("failed to remove build dir");
 			}
 
 			if !build_dir.join("qpkg.configured").exists() {
-				println!("info: configuring {}", entry.name);
-				execute_step(&recipe.configure);
+				if !execute_step(&recipe.builtin("configure"), "configure") {
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "configure", &step_log_path("configure"));
+					continue;
+				}
 				touch_file(build_dir.join("qpkg.configured"));
 			}
+			if let Err(step) = execute_named_steps("configure") {
+				package_failed(&mut failures, keep_going, &entry.name, entry.host, &step, &step_log_path(&step));
+				continue;
+			}
 		}
 
 		if !entry.user_specified || do_build {
 			if entry.user_specified && force_build {
-				println!("info: forcing build for {}", entry.name);
+				output::info(format_args!("forcing build for {}", entry.name));
 				remove_file(build_dir.join("qpkg.built"));
 			}
 
 			if !build_dir.join("qpkg.built").exists() {
-				println!("info: building {}", entry.name);
-				execute_step(&recipe.build);
+				if !execute_step(&recipe.builtin("build"), "build") {
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "build", &step_log_path("build"));
+					continue;
+				}
 				touch_file(build_dir.join("qpkg.built"));
 			}
+			if let Err(step) = execute_named_steps("build") {
+				package_failed(&mut failures, keep_going, &entry.name, entry.host, &step, &step_log_path(&step));
+				continue;
+			}
+		}
+
+		// the tests run every time and a failing one doesn't stop the other packages
+		if test && entry.user_specified {
+			if recipe.check.args.is_empty() {
+				output::info(format_args!("{} has no check step", display_name(&entry.name, entry.host)));
+				untested.push((entry.name.clone(), entry.host));
+			} else if execute_step(&[&recipe.check], "check") {
+				tests_passed.push((entry.name.clone(), entry.host));
+			} else {
+				tests_failed.push(Failure {
+					name: entry.name.clone(),
+					host: entry.host,
+					step: "check".to_string(),
+					log: step_log_path("check")
+				});
+			}
 		}
 
 		if !entry.user_specified || do_install {
 			if entry.user_specified && force_install {
-				println!("info: forcing install for {}", entry.name);
+				output::info(format_args!("forcing install for {}", entry.name));
 				remove_file(build_dir.join("qpkg.installed"));
 			}
 
 			if !build_dir.join("qpkg.installed").exists() {
-				println!("info: installing {}", entry.name);
-				execute_step(&recipe.install);
+				if !execute_step(&recipe.builtin("install"), "install") {
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "install", &step_log_path("install"));
+					continue;
+				}
 				touch_file(build_dir.join("qpkg.installed"));
+				installed_now = true;
 			}
-		}
-
-		let pkg_meta_dir = meta_dir.join(&entry.name);
-		let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
-
-		if !entry.user_specified && !installed.trim().is_empty() {
-			continue;
-		}
-
-		if !entry.host && (!entry.user_specified || do_sync) {
-			if !dest_dir.exists() {
-				eprintln!("error: dest dir {} doesn't exist", dest_dir.display());
-				exit(1);
+			if let Err(step) = execute_named_steps("install") {
+				package_failed(&mut failures, keep_going, &entry.name, entry.host, &step, &step_log_path(&step));
+				continue;
 			}
+		}
 
-			let abs_dest_dir = dest_dir.canonicalize().expect("failed to canonizalize dest dir");
+		record_source_state(&work_dir, &build_dir);
 
-			let mut files = String::new();
+		let installed = read_to_string(pkg_meta_dir.join("FILES")).unwrap_or_default();
 
-			for file in WalkDir::new(&abs_dest_dir) {
-				let file = file.unwrap();
-				let path = file.path().strip_prefix(&abs_dest_dir).unwrap();
+		let already_synced = !entry.user_specified && !installed.trim().is_empty();
 
-				if state.config.general.strip_la_files {
-					if path.extension().is_some_and(|ext| ext == "la") {
-						continue;
-					}
-				}
-				if state.config.general.strip_docs {
-					if path.starts_with(doc_path) {
-						continue;
-					}
-				}
+		let sync_root = if entry.host {
+			host_prefix.as_deref()
+		} else {
+			Some(sysroot)
+		};
 
-				let full_path = sysroot.join(path);
+		let should_sync = should_sync(
+			entry.host,
+			entry.user_specified,
+			already_synced,
+			installed_now,
+			local_src.is_some(),
+			do_sync);
 
-				if file.file_type().is_dir() {
-					create_dir(&full_path);
-				} else if file.file_type().is_symlink() {
-					let orig = std::fs::read_link(file.path())
-						.expect("failed to resolve symlink");
-					match std::os::unix::fs::symlink(orig, &full_path) {
-						Ok(_) => {},
-						Err(e) => {
-							if e.kind() != std::io::ErrorKind::AlreadyExists {
-								eprintln!("error: failed to create symlink {}: {}", full_path.display(), e);
-								exit(1);
-							}
-						}
-					}
-				} else {
-					if full_path.exists() {
-						let mut perms = full_path
-							.metadata()
-							.expect("failed to query file metadata")
-							.permissions();
-						// owner/group write
-						perms.set_mode(perms.mode() | 0o220);
-						std::fs::set_permissions(&full_path, perms)
-							.expect("failed to set file permissions");
-					}
+		if let Some(sync_root) = sync_root && should_sync {
+			let timer = Timer::start();
+			output::emit(Event::StepStarted { package: &entry.name, step: "sync" });
 
-					match std::fs::copy(file.path(), &full_path) {
-						Ok(_) => {},
-						Err(e) => {
-							eprintln!("error: failed to copy {} to {}: {}", path.display(), full_path.display(), e);
-							exit(1);
-						}
-					}
+			let epoch = if state.config.general.clamp_mtimes {
+				read_epoch()
+			} else {
+				None
+			};
+			let (added, removed, total) = match installed::sync(&state, &dest_dir, sync_root, &pkg_meta_dir, epoch) {
+				Ok(counts) => counts,
+				Err(message) => {
+					let log_path = step_log_path("sync");
+					writeln!(logs::open_log(&log_path), "error: {}", message).ok();
+					output::emit(Event::Error {
+						package: Some(&entry.name),
+						step: Some("sync"),
+						command: None,
+						message
+					});
+					package_failed(&mut failures, keep_going, &entry.name, entry.host, "sync", &log_path);
+					continue;
 				}
+			};
 
-				files += path.to_str().unwrap();
-				files.push('\n');
+			if !entry.host {
+				let depends: Vec<_> = recipe.general.depends.iter()
+					.map(|dep| binary_alternatives.iter()
+						.find(|((_, host), name)| !host && *name == dep)
+						.map_or(dep, |((alternative, _), _)| alternative)
+						.clone())
+					.collect();
+				installed::record(&pkg_meta_dir, entry.user_specified, &depends);
 			}
 
-			for name in installed.lines().rev()
-				.filter(|name| files.lines().find(|line| line == name).is_none()) {
-				let name = name.trim();
-				if name.is_empty() {
-					continue;
-				}
+			output::emit(Event::Synced { package: &entry.name, added, removed, total });
+			finish_step(&state, &entry.name, entry.host, "sync", &timer);
+		}
 
-				let path = sysroot.join(name);
-				remove_path(&path);
-			}
+		output::emit(Event::PackageFinished {
+			package: &entry.name,
+			host: entry.host,
+			duration: package_start.elapsed().as_secs_f64()
+		});
+		built.push((entry.name, entry.host));
+	}
 
-			create_dir(&pkg_meta_dir);
+	if dry_run {
+		plan::print(&plan);
+		return;
+	}
 
-			match write(pkg_meta_dir.join("FILES"), files) {
-				Ok(_) => {}
-				Err(e) => {
-					eprintln!("error: failed to write {}: {}", pkg_meta_dir.join("FILES").display(), e);
-					exit(1);
-				}
-			}
+	if test {
+		print_test_summary(&tests_passed, &tests_failed, &untested);
+	}
+
+	if keep_going {
+		print_summary(&built, &skipped, &failures);
+		if !failures.is_empty() {
+			exit(1);
 		}
 	}
+
+	if !tests_failed.is_empty() {
+		exit(1);
+	}
 }
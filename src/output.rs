@@ -0,0 +1,176 @@
+use std::fmt::Display;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use serde::Serialize;
+use crate::plan::PackagePlan;
+
+#[derive(Copy, Clone, PartialEq, clap::ValueEnum)]
+pub enum Format {
+	Text,
+	Json
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+pub fn set_format(format: Format) {
+	FORMAT.set(format).ok();
+}
+
+pub fn format() -> Format {
+	*FORMAT.get().unwrap_or(&Format::Text)
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+	PackageStarted {
+		package: &'a str,
+		host: bool
+	},
+	PackageFinished {
+		package: &'a str,
+		host: bool,
+		duration: f64
+	},
+	StepStarted {
+		package: &'a str,
+		step: &'a str
+	},
+	StepFinished {
+		package: &'a str,
+		step: &'a str,
+		duration: f64
+	},
+	FetchStarted {
+		package: &'a str,
+		source: &'a str,
+		method: &'a str
+	},
+	FetchFinished {
+		package: &'a str,
+		source: &'a str,
+		bytes: u64,
+		duration: f64
+	},
+	PatchApplied {
+		package: &'a str,
+		patch: &'a str
+	},
+	// whether a patch still applies to a new version, clean, offset, fuzz or fail
+	PatchChecked {
+		package: &'a str,
+		patch: &'a str,
+		status: &'a str
+	},
+	Synced {
+		package: &'a str,
+		added: usize,
+		removed: usize,
+		total: usize
+	},
+	Error {
+		package: Option<&'a str>,
+		step: Option<&'a str>,
+		command: Option<&'a str>,
+		message: String
+	},
+	Warning {
+		package: Option<&'a str>,
+		message: String
+	},
+	ReproChecked {
+		package: &'a str,
+		reproducible: bool,
+		// where the two builds are kept
+		builds: String
+	},
+	Summary {
+		built: Vec<String>,
+		skipped: Vec<String>,
+		failed: Vec<FailedPackage<'a>>
+	},
+	TestSummary {
+		passed: Vec<String>,
+		failed: Vec<FailedPackage<'a>>,
+		// packages without a check step
+		untested: Vec<String>
+	},
+	Plan {
+		packages: &'a [PackagePlan]
+	}
+}
+
+#[derive(Serialize)]
+pub struct FailedPackage<'a> {
+	pub package: String,
+	pub step: &'a str,
+	pub log: &'a str
+}
+
+impl Event<'_> {
+	fn text(&self) -> Option<String> {
+		match self {
+			Event::StepStarted { package, step } => Some(match *step {
+				"prepare" => format!("preparing source for {}", package),
+				"configure" => format!("configuring {}", package),
+				"build" => format!("building {}", package),
+				"install" => format!("installing {}", package),
+				"check" => format!("checking {}", package),
+				"sync" => format!("syncing {}", package),
+				step => format!("running {} for {}", step, package)
+			}),
+			Event::FetchStarted { source, method, .. } => {
+				Some(format!("fetching {} using {}", source, method))
+			},
+			Event::PatchApplied { patch, .. } => Some(format!("applied patch {}", patch)),
+			Event::PatchChecked { patch, status, .. } => Some(format!("  {} {}", status, patch)),
+			Event::ReproChecked { package, reproducible: true, .. } => Some(format!("{} is reproducible", package)),
+			_ => None
+		}
+	}
+}
+
+pub fn emit(event: Event) {
+	match format() {
+		Format::Text => {
+			if let Event::Error { message, .. } = &event {
+				eprintln!("error: {}", message);
+			} else if let Event::ReproChecked { package, reproducible: false, builds } = &event {
+				eprintln!("error: {} is not reproducible, the builds are in {}", package, builds);
+			} else if let Event::Warning { message, .. } = &event {
+				eprintln!("warning: {}", message);
+			} else if let Some(text) = event.text() {
+				println!("info: {}", text);
+			}
+		},
+		Format::Json => {
+			println!("{}", serde_json::to_string(&event).expect("failed to serialize event"));
+		}
+	}
+}
+
+pub fn info(msg: impl Display) {
+	if format() == Format::Text {
+		println!("info: {}", msg);
+	}
+}
+
+pub fn error(msg: impl Display) {
+	emit(Event::Error { package: None, step: None, command: None, message: msg.to_string() });
+}
+
+// plain output that isn't an event goes to stderr in json mode
+pub fn print(msg: impl Display) {
+	match format() {
+		Format::Text => println!("{}", msg),
+		Format::Json => eprintln!("{}", msg)
+	}
+}
+
+// in json mode stdout is reserved for events
+pub fn child_stdout() -> Stdio {
+	match format() {
+		Format::Text => Stdio::inherit(),
+		Format::Json => Stdio::from(std::io::stderr())
+	}
+}
@@ -0,0 +1,304 @@
+use std::fs::{read_dir, read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, ExitStatus};
+use crate::build::{PatchEntry, Recipe};
+use crate::output;
+
+pub struct Patch {
+	// as written in the series
+	file: String,
+	pub path: PathBuf,
+	pub strip: u32,
+	versions: Vec<String>,
+	targets: Vec<String>
+}
+
+impl Patch {
+	fn new(dir: &Path, file: &str) -> Self {
+		Self {
+			file: file.to_string(),
+			path: dir.join(file),
+			strip: 1,
+			versions: Vec::new(),
+			targets: Vec::new()
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		self.path.file_name().unwrap().to_str().unwrap()
+	}
+
+	fn applies_to(&self, version: &str, target: &str) -> bool {
+		(self.versions.is_empty() || self.versions.iter().any(|pattern| glob_match(pattern, version))) &&
+			(self.targets.is_empty() || self.targets.iter().any(|pattern| glob_match(pattern, target)))
+	}
+
+	// the same patch under another file name, like the ones written by gen-patch --series
+	pub fn renamed(&self, dir: &Path, file: &str) -> Self {
+		Self {
+			strip: self.strip,
+			versions: self.versions.clone(),
+			targets: self.targets.clone(),
+			..Self::new(dir, file)
+		}
+	}
+
+	// a patch that was rebased onto version only applies to it if it was limited to a version before
+	pub fn rebased(mut self, version: &str) -> Self {
+		if !self.versions.is_empty() {
+			self.versions = vec![version.to_string()];
+		}
+		self
+	}
+
+	pub fn series_line(&self) -> String {
+		let mut line = self.file.clone();
+		if self.strip != 1 {
+			line += &format!(" -p{}", self.strip);
+		}
+		if !self.versions.is_empty() {
+			line += &format!(" version={}", self.versions.join(","));
+		}
+		if !self.targets.is_empty() {
+			line += &format!(" target={}", self.targets.join(","));
+		}
+		line
+	}
+}
+
+pub struct Applied {
+	pub status: ExitStatus,
+	// the hunks that needed fuzz or an offset to apply
+	pub warnings: Vec<String>
+}
+
+// only * is special, it matches any amount of characters
+fn glob_match(pattern: &str, value: &str) -> bool {
+	match pattern.split_once('*') {
+		None => pattern == value,
+		Some((prefix, rest)) => {
+			let Some(value) = value.strip_prefix(prefix) else {
+				return false;
+			};
+			(0..=value.len())
+				.filter(|i| value.is_char_boundary(*i))
+				.any(|i| glob_match(rest, &value[i..]))
+		}
+	}
+}
+
+pub fn dir(recipes_dir: &Path, name: &str) -> PathBuf {
+	std::path::absolute(recipes_dir.join(name).join("patches"))
+		.expect("failed to get absolute patches dir")
+}
+
+// lines look like `name.patch -p0 version=1.2.*,1.3 target=*-musl`, # starts a comment
+fn parse_series(dir: &Path, path: &Path) -> Vec<Patch> {
+	let series = match read_to_string(path) {
+		Ok(series) => series,
+		Err(e) => {
+			output::error(format_args!("failed to read {}: {}", path.display(), e));
+			exit(1);
+		}
+	};
+
+	let mut patches = Vec::new();
+	for (num, line) in series.lines().enumerate() {
+		let line = line.split_once('#').map_or(line, |(line, _)| line);
+		let mut words = line.split_whitespace();
+		let Some(file) = words.next() else {
+			continue;
+		};
+
+		let mut patch = Patch::new(dir, file);
+		for word in words {
+			let invalid = || -> ! {
+				output::error(format_args!("{}:{}: invalid patch option {}", path.display(), num + 1, word));
+				exit(1);
+			};
+
+			if let Some(strip) = word.strip_prefix("-p") {
+				patch.strip = strip.parse().unwrap_or_else(|_| invalid());
+			} else if let Some(versions) = word.strip_prefix("version=") {
+				patch.versions.extend(versions.split(',').map(String::from));
+			} else if let Some(targets) = word.strip_prefix("target=") {
+				patch.targets.extend(targets.split(',').map(String::from));
+			} else {
+				invalid();
+			}
+		}
+		patches.push(patch);
+	}
+	patches
+}
+
+// the patches list of the recipe, the series file or else the patch files in the patches dir
+// sorted by name, including the ones whose conditions don't match
+pub fn all(recipe: &Recipe, dir: &Path) -> Vec<Patch> {
+	let patches = if let Some(entries) = &recipe.general.patches {
+		entries.iter().map(|entry| match entry {
+			PatchEntry::File(file) => Patch::new(dir, file),
+			PatchEntry::Options { file, strip, versions, targets } => Patch {
+				strip: *strip,
+				versions: versions.clone(),
+				targets: targets.clone(),
+				..Patch::new(dir, file)
+			}
+		}).collect()
+	} else if dir.join("series").exists() {
+		parse_series(dir, &dir.join("series"))
+	} else {
+		let Ok(entries) = read_dir(dir) else {
+			return Vec::new();
+		};
+
+		let mut files: Vec<_> = entries.flatten()
+			.filter(|entry| entry.path().is_file())
+			.filter_map(|entry| entry.file_name().into_string().ok())
+			.filter(|file| file.ends_with(".patch") || file.ends_with(".diff"))
+			.collect();
+		files.sort();
+		files.iter().map(|file| Patch::new(dir, file)).collect()
+	};
+
+	for patch in &patches {
+		if !patch.path.is_file() {
+			output::error(format_args!("patch {} of {} doesn't exist", patch.path.display(), recipe.general.name));
+			exit(1);
+		}
+	}
+
+	patches
+}
+
+// the patches to apply in order, host packages match the target `host`
+pub fn series(recipe: &Recipe, dir: &Path, target: &str) -> Vec<Patch> {
+	all(recipe, dir)
+		.into_iter()
+		.filter(|patch| patch.applies_to(&recipe.general.version, target))
+		.collect()
+}
+
+pub fn command(patch: &Patch, work_dir: &Path) -> Command {
+	let mut cmd = Command::new("patch");
+	cmd.arg("-N")
+		.arg(format!("-p{}", patch.strip))
+		.args(["-i", patch.path.to_str().unwrap()])
+		.current_dir(work_dir);
+	cmd
+}
+
+// the output is kept to find fuzz and offsets, patch also never prompts since stdin is closed
+pub fn apply(patch: &Patch, work_dir: &Path, log: &File, dry_run: bool) -> Applied {
+	let mut cmd = command(patch, work_dir);
+	if dry_run {
+		cmd.arg("--dry-run");
+	}
+
+	let output = match cmd.output() {
+		Ok(output) => output,
+		Err(e) => {
+			output::error(format_args!("failed to spawn patch: {}", e));
+			exit(1);
+		}
+	};
+
+	let mut log = log;
+	log.write_all(&output.stdout).ok();
+	log.write_all(&output.stderr).ok();
+
+	// a dry run only checks whether the patch applies, the real one is shown like other commands
+	if !dry_run {
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		if output::format() == output::Format::Json {
+			eprint!("{}", stdout);
+		} else {
+			print!("{}", stdout);
+		}
+		eprint!("{}", String::from_utf8_lossy(&output.stderr));
+	}
+
+	let warnings = String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.filter(|line| line.contains("with fuzz") || line.contains("(offset"))
+		.map(|line| line.trim().to_string())
+		.collect();
+
+	Applied {
+		status: output.status,
+		warnings
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs::{create_dir_all, remove_dir_all, write};
+	use super::*;
+	use crate::git;
+
+	fn git(dir: &Path, args: &[&str]) {
+		assert!(Command::new("git").args(args).current_dir(dir).status().unwrap().success());
+	}
+
+	fn options(patches: &[Patch]) -> Vec<(u32, Vec<String>, Vec<String>)> {
+		patches.iter().map(|patch| (patch.strip, patch.versions.clone(), patch.targets.clone())).collect()
+	}
+
+	#[test]
+	fn export_keeps_series_options() {
+		let root = std::env::temp_dir().join(format!("qpkg-series-{}", std::process::id()));
+		remove_dir_all(&root).ok();
+		let work = root.join("work");
+		let dir = root.join("patches");
+		create_dir_all(&work).unwrap();
+		create_dir_all(&dir).unwrap();
+		let log = File::create(root.join("log")).unwrap();
+
+		write(work.join("a.txt"), "one\n").unwrap();
+		write(work.join("b.txt"), "two\n").unwrap();
+		git(&work, &["init", "-q"]);
+		git(&work, &["config", "user.name", "qpkg"]);
+		git(&work, &["config", "user.email", "qpkg@localhost"]);
+		git(&work, &["add", "."]);
+		git(&work, &["commit", "-qm", "base"]);
+		git(&work, &["tag", git::BASE_TAG]);
+
+		write(dir.join("p0.patch"), "--- a.txt\n+++ a.txt\n@@ -1 +1 @@\n-one\n+uno\n").unwrap();
+		write(dir.join("p2.patch"), "--- x/a/b.txt\n+++ y/b/b.txt\n@@ -1 +1 @@\n-two\n+dos\n").unwrap();
+		write(dir.join("later.patch"), "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+eins\n").unwrap();
+		write(
+			dir.join("series"),
+			"p0.patch -p0 version=1.*\np2.patch -p2 target=x86_64-*,aarch64-*\nlater.patch version=2.*\n").unwrap();
+
+		let all = parse_series(&dir, &dir.join("series"));
+		let applied: Vec<_> = parse_series(&dir, &dir.join("series"))
+			.into_iter()
+			.filter(|patch| patch.applies_to("1.0", "x86_64-linux"))
+			.collect();
+		assert_eq!(applied.len(), 2);
+		for patch in &applied {
+			assert!(apply(patch, &work, &log, false).status.success());
+			git::commit_patch(&work, patch);
+		}
+
+		git::export_series(&work, &dir, &all, &applied);
+
+		let exported = parse_series(&dir, &dir.join("series"));
+		assert_eq!(options(&exported), options(&all));
+		assert_eq!(exported[0].file, "0001-p0.patch");
+		assert_eq!(exported[1].file, "0002-p2.patch");
+		assert_eq!(exported[2].file, "later.patch");
+		assert!(!dir.join("p0.patch").exists());
+
+		// the exported patches apply to the base with the strip level of the originals
+		git(&work, &["checkout", "-q", git::BASE_TAG]);
+		for patch in &exported[..2] {
+			assert!(apply(patch, &work, &log, false).status.success());
+		}
+		assert_eq!(read_to_string(work.join("a.txt")).unwrap(), "uno\n");
+		assert_eq!(read_to_string(work.join("b.txt")).unwrap(), "dos\n");
+
+		remove_dir_all(&root).ok();
+	}
+}
@@ -0,0 +1,68 @@
+use serde::Serialize;
+use crate::display_name;
+use crate::output::{self, Event, Format};
+
+#[derive(Serialize)]
+pub struct SyncPlan {
+	pub added: Vec<String>,
+	pub removed: Vec<String>,
+	// the files come from the current dest dir which install is going to regenerate
+	pub after_install: bool
+}
+
+#[derive(Serialize)]
+pub struct PackagePlan {
+	pub package: String,
+	pub host: bool,
+	pub binary_alternative_of: Option<String>,
+	pub fetch: Vec<String>,
+	pub steps: Vec<String>,
+	pub sync: Option<SyncPlan>
+}
+
+pub fn print(plan: &[PackagePlan]) {
+	if output::format() == Format::Json {
+		output::emit(Event::Plan { packages: plan });
+		return;
+	}
+
+	if plan.is_empty() {
+		println!("nothing to do");
+		return;
+	}
+
+	println!("plan:");
+	for pkg in plan {
+		match &pkg.binary_alternative_of {
+			Some(name) => println!(
+				"    {} (binary alternative of {})",
+				display_name(&pkg.package, pkg.host),
+				name),
+			None => println!("    {}", display_name(&pkg.package, pkg.host))
+		}
+
+		for src in &pkg.fetch {
+			println!("        fetch {}", src);
+		}
+		if !pkg.steps.is_empty() {
+			println!("        steps: {}", pkg.steps.join(" "));
+		}
+		if pkg.fetch.is_empty() && pkg.steps.is_empty() && pkg.sync.is_none() {
+			println!("        up to date");
+		}
+
+		if let Some(sync) = &pkg.sync {
+			println!(
+				"        sync: {} added, {} removed{}",
+				sync.added.len(),
+				sync.removed.len(),
+				if sync.after_install { " (before install runs)" } else { "" });
+			for name in &sync.added {
+				println!("            + {}", name);
+			}
+			for name in &sync.removed {
+				println!("            - {}", name);
+			}
+		}
+	}
+}
@@ -0,0 +1,208 @@
+use std::fs::{read_to_string, rename, write};
+use std::path::Path;
+use std::process::{exit, Command};
+use crate::bump::{fetch_sources, unpack};
+use crate::git::{self, BASE_TAG};
+use crate::{build, create_dir, display_name, finalize_recipe, load_finalized_recipe, load_recipe, logs, output, package_dirs, patches, recipes_dir, remove_file, State};
+
+// the branch the new upstream source is committed to
+const UPSTREAM: &str = "upstream";
+
+fn load_version(state: &State, name: &str, host: bool, version: &str) -> build::Recipe {
+	let build_root = Path::new(&state.config.general.build_root);
+	let [root_src_dir, _, dest_dir] = package_dirs(build_root, name, host);
+
+	let mut recipe = load_recipe(&state.config, name, host);
+	recipe.general.version = version.to_string();
+	finalize_recipe(&mut recipe, state, &root_src_dir, &dest_dir);
+	recipe
+}
+
+fn remove_dir(path: &Path) {
+	if path.exists() && let Err(e) = std::fs::remove_dir_all(path) {
+		output::error(format_args!("failed to remove {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+// unpacks the source of recipe into dir and returns its workdir without the git metadata of clones
+fn unpack_tree(recipe: &build::Recipe, archives_dir: &Path, dir: &Path) -> std::path::PathBuf {
+	remove_dir(dir);
+	create_dir(dir);
+	unpack(recipe, archives_dir, dir);
+
+	let tree = dir.join(&recipe.general.workdir);
+	remove_dir(&tree.join(".git"));
+	tree
+}
+
+fn rebase_in_progress(repo: &Path) -> bool {
+	repo.join(".git/rebase-merge").exists() || repo.join(".git/rebase-apply").exists()
+}
+
+fn stop_for_conflicts(name: &str, host: bool, repo: &Path) -> ! {
+	output::error(format_args!(
+		"the patches of {} conflict with the new version, resolve the conflicts in {}, \
+		git add the files and run qpkg patch-refresh --continue {}",
+		display_name(name, host),
+		repo.display(),
+		name));
+	exit(1);
+}
+
+pub fn start(state: &State, name: &str, from: &str, host: bool) {
+	let build_root = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
+	let refresh_dir = build_root.join("refresh").join(name);
+	if refresh_dir.exists() {
+		output::error(format_args!(
+			"a refresh of {} is already in progress, use --continue or --abort",
+			display_name(name, host)));
+		exit(1);
+	}
+
+	let old = load_version(state, name, host, from);
+	let new = load_finalized_recipe(state, name, host);
+	if old.general.version == new.general.version {
+		output::error(format_args!("{} is already at version {}, bump it first", display_name(name, host), from));
+		exit(1);
+	}
+	if old.general.patches.is_some() {
+		output::error(format_args!("the patches of {} are listed in its recipe, remove the list to use a series file", name));
+		exit(1);
+	}
+
+	let archives_dir = build_root.join("archives");
+	create_dir(&archives_dir);
+	let log_path = logs::log_path(&build_root, name, host, "fetch");
+	if !fetch_sources(&old, &archives_dir, &log_path) || !fetch_sources(&new, &archives_dir, &log_path) {
+		exit(1);
+	}
+
+	create_dir(&refresh_dir);
+	let from_path = refresh_dir.join("from");
+	if let Err(e) = write(&from_path, from) {
+		output::error(format_args!("failed to write {}: {}", from_path.display(), e));
+		exit(1);
+	}
+
+	// the old tree becomes the repository the patches are committed to
+	let repo = refresh_dir.join("src");
+	let old_tree = unpack_tree(&old, &archives_dir, &refresh_dir.join("old"));
+	if let Err(e) = rename(&old_tree, &repo) {
+		output::error(format_args!("failed to move {} to {}: {}", old_tree.display(), repo.display(), e));
+		exit(1);
+	}
+	remove_dir(&refresh_dir.join("old"));
+
+	git::git(&repo, &["init", "-q", "-b", "main"]);
+	git::git(&repo, &["add", "-A"]);
+	git::git(&repo, &["commit", "-q", "-m", &format!("{} {}", name, from)]);
+	git::git(&repo, &["tag", BASE_TAG]);
+
+	let target = if host { "host" } else { &state.config.general.target };
+	let patch_log = logs::open_log(&logs::log_path(&build_root, name, host, "refresh"));
+	for patch in patches::series(&old, &patches::dir(recipes_dir(state, host), name), target) {
+		if !patches::apply(&patch, &repo, &patch_log, false).status.success() {
+			output::error(format_args!("patch {} doesn't apply to {} {}", patch.name(), display_name(name, host), from));
+			remove_dir(&refresh_dir);
+			exit(1);
+		}
+		git::commit_patch(&repo, &patch);
+	}
+
+	// the new tree is committed on top of the old one so that git sees the upstream changes as a diff
+	let new_tree = unpack_tree(&new, &archives_dir, &refresh_dir.join("new"));
+	git::git(&repo, &["checkout", "-q", "-b", UPSTREAM, BASE_TAG]);
+	git::git(&repo, &["rm", "-rq", "."]);
+	git::git(&repo, &["clean", "-fdxq"]);
+	let status = Command::new("cp")
+		.arg("-a")
+		.arg(new_tree.join("."))
+		.arg(&repo)
+		.status();
+	if !status.is_ok_and(|status| status.success()) {
+		output::error(format_args!("failed to copy {} to {}", new_tree.display(), repo.display()));
+		exit(1);
+	}
+	remove_dir(&refresh_dir.join("new"));
+	git::git(&repo, &["add", "-A"]);
+	git::git(&repo, &["commit", "-q", "--allow-empty", "-m", &format!("{} {}", name, new.general.version)]);
+	git::git(&repo, &["checkout", "-q", "main"]);
+
+	output::info(format_args!(
+		"rebasing the patches of {} from {} onto {}",
+		display_name(name, host),
+		from,
+		new.general.version));
+
+	let status = Command::new("git")
+		.args(["rebase", "-q", "--onto", UPSTREAM, BASE_TAG, "main"])
+		.current_dir(&repo)
+		.stdout(output::child_stdout())
+		.status();
+	if !status.is_ok_and(|status| status.success()) {
+		stop_for_conflicts(name, host, &repo);
+	}
+
+	finish(state, name, host);
+}
+
+pub fn resume(state: &State, name: &str, host: bool) {
+	let build_root = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
+	let repo = build_root.join("refresh").join(name).join("src");
+	if !repo.exists() {
+		output::error(format_args!("no refresh of {} is in progress", display_name(name, host)));
+		exit(1);
+	}
+
+	// the rebase may have already been continued by hand
+	if rebase_in_progress(&repo) {
+		let status = Command::new("git")
+			.args(["rebase", "--continue"])
+			.env("GIT_EDITOR", "true")
+			.current_dir(&repo)
+			.stdout(output::child_stdout())
+			.status();
+		if !status.is_ok_and(|status| status.success()) || rebase_in_progress(&repo) {
+			stop_for_conflicts(name, host, &repo);
+		}
+	}
+
+	finish(state, name, host);
+}
+
+pub fn abort(state: &State, name: &str, host: bool) {
+	let refresh_dir = Path::new(&state.config.general.build_root).join("refresh").join(name);
+	if !refresh_dir.exists() {
+		output::error(format_args!("no refresh of {} is in progress", display_name(name, host)));
+		exit(1);
+	}
+	remove_dir(&refresh_dir);
+}
+
+fn finish(state: &State, name: &str, host: bool) {
+	let build_root = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
+	let refresh_dir = build_root.join("refresh").join(name);
+	let repo = refresh_dir.join("src");
+
+	let from = read_to_string(refresh_dir.join("from")).unwrap_or_default();
+	let old = load_version(state, name, host, from.trim());
+	let new = load_finalized_recipe(state, name, host);
+	let target = if host { "host" } else { &state.config.general.target };
+	let dir = patches::dir(recipes_dir(state, host), name);
+
+	// the commits are now on top of the new version, the refreshed patches keep their options
+	// except for the versions they're limited to, which would still be the old ones
+	let applied: Vec<_> = patches::series(&old, &dir, target).into_iter()
+		.map(|patch| patch.rebased(&new.general.version))
+		.collect();
+	git::git(&repo, &["tag", "-f", BASE_TAG, UPSTREAM]);
+	git::export_series(&repo, &dir, &patches::all(&old, &dir), &applied);
+
+	let [root_src_dir, _, _] = package_dirs(&build_root, name, host);
+	remove_file(root_src_dir.join("qpkg.prepared"));
+	remove_dir(&refresh_dir);
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+use crate::build::Recipe;
+use crate::{output, source_path};
+
+fn mtime(meta: &Metadata) -> Option<u64> {
+	meta.modified().ok()?
+		.duration_since(UNIX_EPOCH).ok()
+		.map(|time| time.as_secs())
+}
+
+pub fn git_commit_time(repo: &Path) -> Option<u64> {
+	let output = Command::new("git")
+		.args(["log", "-1", "--format=%ct"])
+		.current_dir(repo)
+		.output()
+		.ok()?;
+	if !output.status.success() {
+		return None;
+	}
+
+	String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+fn newest_mtime(dir: &Path) -> Option<u64> {
+	WalkDir::new(dir)
+		.into_iter()
+		.flatten()
+		.filter(|file| !file.file_type().is_dir())
+		.filter(|file| !file.file_name().to_str().is_some_and(|name| name.starts_with("qpkg.")))
+		.filter_map(|file| file.path().symlink_metadata().ok().and_then(|meta| mtime(&meta)))
+		.max()
+}
+
+// the recipe override, the commit date of git sources or the newest member of unpacked archives
+pub fn source_date_epoch(recipe: &Recipe, archives_dir: &Path, root_src_dir: &Path) -> Option<u64> {
+	if let Some(epoch) = recipe.general.source_date_epoch {
+		return Some(epoch);
+	}
+
+	let mut epoch = None;
+	for src in &recipe.general.src {
+		let path = source_path(recipe, archives_dir, src);
+
+		let time = if src.contains(".git") {
+			git_commit_time(&path)
+		} else if !recipe.general.no_auto_unpack {
+			newest_mtime(root_src_dir)
+		} else {
+			path.metadata().ok().and_then(|meta| mtime(&meta))
+		};
+
+		epoch = epoch.max(time);
+	}
+
+	epoch
+}
+
+pub fn clamp_mtime(path: &Path, epoch: u64) {
+	let Ok(meta) = path.symlink_metadata() else {
+		return;
+	};
+	if mtime(&meta).is_none_or(|time| time <= epoch) {
+		return;
+	}
+
+	let path = CString::new(path.as_os_str().as_bytes()).expect("path contains a nul byte");
+	let time = libc::timespec {
+		tv_sec: epoch as libc::time_t,
+		tv_nsec: 0
+	};
+	let times = [time, time];
+	if unsafe {
+		libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+	} != 0 {
+		output::error(format_args!(
+			"failed to set mtime of {}: {}",
+			path.to_string_lossy(),
+			std::io::Error::last_os_error()));
+		std::process::exit(1);
+	}
+}
+
+#[derive(PartialEq)]
+enum TreeEntry {
+	Dir { mode: u32 },
+	Symlink { target: PathBuf },
+	File { mode: u32, hash: String }
+}
+
+pub fn hash_file(path: &Path) -> String {
+	let data = match std::fs::read(path) {
+		Ok(data) => data,
+		Err(e) => {
+			output::error(format_args!("failed to read {}: {}", path.display(), e));
+			std::process::exit(1);
+		}
+	};
+
+	hash_bytes(&data)
+}
+
+pub fn hash_bytes(data: &[u8]) -> String {
+	Sha256::digest(data)
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+fn scan_tree(root: &Path) -> BTreeMap<PathBuf, TreeEntry> {
+	let mut entries = BTreeMap::new();
+
+	for file in WalkDir::new(root).min_depth(1) {
+		let file = file.expect("failed to walk tree");
+		let path = file.path().strip_prefix(root).unwrap().to_path_buf();
+		let meta = file.path().symlink_metadata().expect("failed to query file metadata");
+		let mode = meta.permissions().mode() & 0o7777;
+
+		let entry = if file.file_type().is_dir() {
+			TreeEntry::Dir { mode }
+		} else if file.file_type().is_symlink() {
+			TreeEntry::Symlink {
+				target: std::fs::read_link(file.path()).expect("failed to resolve symlink")
+			}
+		} else {
+			TreeEntry::File { mode, hash: hash_file(file.path()) }
+		};
+
+		entries.insert(path, entry);
+	}
+
+	entries
+}
+
+fn print_file_diff(a: &Path, b: &Path) {
+	let (Ok(a_data), Ok(b_data)) = (std::fs::read(a), std::fs::read(b)) else {
+		return;
+	};
+
+	let is_text = |data: &[u8]| !data.contains(&0) && std::str::from_utf8(data).is_ok();
+	if is_text(&a_data) && is_text(&b_data) {
+		if let Ok(output) = Command::new("diff").arg("-u").arg(a).arg(b).output() {
+			for line in String::from_utf8_lossy(&output.stdout).lines().take(50) {
+				output::print(format_args!("        {}", line));
+			}
+		}
+	} else {
+		let offset = a_data.iter()
+			.zip(b_data.iter())
+			.position(|(a, b)| a != b)
+			.unwrap_or(a_data.len().min(b_data.len()));
+		output::print(format_args!("        first difference at byte offset {:#x}", offset));
+	}
+}
+
+// prints the differences between two trees and returns whether they are identical
+pub fn compare_trees(a: &Path, b: &Path) -> bool {
+	let a_entries = scan_tree(a);
+	let b_entries = scan_tree(b);
+
+	let mut same = true;
+
+	for (path, a_entry) in &a_entries {
+		let Some(b_entry) = b_entries.get(path) else {
+			output::print(format_args!("only in the first build: {}", path.display()));
+			same = false;
+			continue;
+		};
+		if a_entry == b_entry {
+			continue;
+		}
+
+		same = false;
+		output::print(format_args!("differs: {}", path.display()));
+
+		match (a_entry, b_entry) {
+			(TreeEntry::File { mode: a_mode, hash: a_hash }, TreeEntry::File { mode: b_mode, hash: b_hash }) => {
+				if a_mode != b_mode {
+					output::print(format_args!("    mode {:04o} vs {:04o}", a_mode, b_mode));
+				}
+				if a_hash != b_hash {
+					output::print(format_args!("    sha256 {} vs {}", a_hash, b_hash));
+					print_file_diff(&a.join(path), &b.join(path));
+				}
+			},
+			(TreeEntry::Dir { mode: a_mode }, TreeEntry::Dir { mode: b_mode }) => {
+				output::print(format_args!("    mode {:04o} vs {:04o}", a_mode, b_mode));
+			},
+			(TreeEntry::Symlink { target: a_target }, TreeEntry::Symlink { target: b_target }) => {
+				output::print(format_args!("    symlink to {} vs {}", a_target.display(), b_target.display()));
+			},
+			_ => output::print("    file type differs")
+		}
+	}
+
+	for path in b_entries.keys().filter(|path| !a_entries.contains_key(*path)) {
+		output::print(format_args!("only in the second build: {}", path.display()));
+		same = false;
+	}
+
+	same
+}
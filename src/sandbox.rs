@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// host directories the toolchain and the shell live in, visible read-only
+const SYSTEM_DIRS: [&str; 7] = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+
+// a bubblewrap sandbox with its own user, mount and pid namespaces
+pub struct Sandbox {
+	pub writable: Vec<PathBuf>,
+	pub readonly: Vec<PathBuf>
+}
+
+impl Sandbox {
+	// the program is found on the host, so its dir is bound when nothing else makes it visible
+	pub fn command(&self, program: &Path, cwd: &Path, network: bool) -> Command {
+		let mut cmd = Command::new("bwrap");
+		cmd.args(["--unshare-user", "--unshare-pid", "--die-with-parent"]);
+		if !network {
+			cmd.arg("--unshare-net");
+		}
+
+		for dir in SYSTEM_DIRS {
+			let path = Path::new(dir);
+			if path.is_symlink() {
+				let target = std::fs::read_link(path).expect("failed to resolve symlink");
+				cmd.arg("--symlink").arg(target).arg(dir);
+			} else if path.exists() {
+				cmd.args(["--ro-bind", dir, dir]);
+			}
+		}
+
+		cmd.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+
+		for path in &self.readonly {
+			cmd.arg("--ro-bind-try").arg(path).arg(path);
+		}
+		for path in &self.writable {
+			cmd.arg("--bind").arg(path).arg(path);
+		}
+
+		let dirs = [program.parent(), program.canonicalize().ok().as_deref().and_then(Path::parent)]
+			.into_iter()
+			.flatten()
+			.filter(|dir| dir.is_absolute())
+			.map(Path::to_path_buf)
+			.collect::<Vec<_>>();
+		for dir in dirs {
+			let visible = SYSTEM_DIRS.iter().any(|system| dir.starts_with(system))
+				|| self.readonly.iter().chain(&self.writable).any(|bound| dir.starts_with(bound));
+			if !visible {
+				cmd.arg("--ro-bind-try").arg(&dir).arg(&dir);
+			}
+		}
+
+		cmd.arg("--chdir").arg(cwd).arg("--").arg(program);
+		cmd
+	}
+}
@@ -0,0 +1,277 @@
+use std::fs::{read_dir, write};
+use std::path::Path;
+use std::process::{exit, Command};
+use toml::Value;
+use crate::{archive_name, create_dir, display_name, fetch_command, is_tarball, logs, output, recipes_dir, remove_file, source_to_name, State, TARBALL_EXTENSIONS};
+
+#[derive(Copy, Clone, PartialEq)]
+enum BuildSystem {
+	Autotools,
+	Meson,
+	Cmake,
+	Cargo
+}
+
+impl BuildSystem {
+	fn detect(files: &[String]) -> Option<Self> {
+		let has = |name: &str| files.iter().any(|file| file == name);
+		if has("meson.build") {
+			Some(BuildSystem::Meson)
+		} else if has("CMakeLists.txt") {
+			Some(BuildSystem::Cmake)
+		} else if has("configure") {
+			Some(BuildSystem::Autotools)
+		} else if has("Cargo.toml") {
+			Some(BuildSystem::Cargo)
+		} else {
+			None
+		}
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			BuildSystem::Autotools => "autotools",
+			BuildSystem::Meson => "meson",
+			BuildSystem::Cmake => "cmake",
+			BuildSystem::Cargo => "cargo"
+		}
+	}
+
+	// parts of template names that usually mean the template is for this build system
+	fn template_names(self) -> &'static [&'static str] {
+		match self {
+			BuildSystem::Autotools => &["autotools", "autoconf", "configure"],
+			BuildSystem::Meson => &["meson"],
+			BuildSystem::Cmake => &["cmake"],
+			BuildSystem::Cargo => &["cargo", "rust"]
+		}
+	}
+
+	// used when no template matches
+	fn steps(self) -> [(&'static str, &'static str); 3] {
+		match self {
+			BuildSystem::Autotools => [
+				("configure", "@SRCDIR@/configure --prefix=/usr --host=@TARGET@"),
+				("build", "make -j@THREADS@"),
+				("install", "make DESTDIR=@DESTDIR@ install")
+			],
+			BuildSystem::Meson => [
+				("configure", "meson setup . @SRCDIR@ --prefix=/usr"),
+				("build", "meson compile -j @THREADS@"),
+				("install", "meson install --destdir @DESTDIR@")
+			],
+			BuildSystem::Cmake => [
+				("configure", "cmake -S @SRCDIR@ -B . -DCMAKE_INSTALL_PREFIX=/usr"),
+				("build", "cmake --build . -j @THREADS@"),
+				("install", "DESTDIR=@DESTDIR@ cmake --install .")
+			],
+			BuildSystem::Cargo => [
+				("configure", ""),
+				("build", "cargo build --release --manifest-path @SRCDIR@/Cargo.toml --target-dir . -j @THREADS@"),
+				("install", "cargo install --path @SRCDIR@ --root @DESTDIR@/usr --target-dir .")
+			]
+		}
+	}
+}
+
+fn strip_archive_extension(name: &str) -> &str {
+	for ext in TARBALL_EXTENSIONS {
+		if let Some(stem) = name.strip_suffix(ext) {
+			return stem;
+		}
+	}
+	name
+}
+
+// the version in names like foo-1.2.3, foo_1.2 or v1.2.3
+fn version_from_name(name: &str) -> Option<&str> {
+	let starts_with_digit = |s: &str| s.chars().next().is_some_and(|c| c.is_ascii_digit());
+
+	if let Some(version) = name.strip_prefix('v') && starts_with_digit(version) {
+		return Some(version);
+	}
+
+	name.rmatch_indices(['-', '_'])
+		.map(|(pos, _)| &name[pos + 1..])
+		.find(|version| starts_with_digit(version))
+		.map(|version| version.strip_prefix('v').unwrap_or(version))
+}
+
+// returns the workdir and the files in it
+fn list_archive(path: &Path) -> (String, Vec<String>) {
+	let output = match Command::new("tar").arg("-tf").arg(path).output() {
+		Ok(output) => output,
+		Err(e) => {
+			output::error(format_args!("failed to spawn tar: {}", e));
+			exit(1);
+		}
+	};
+	if !output.status.success() {
+		output::error(format_args!("failed to list {}: tar failed with {}", path.display(), output.status));
+		exit(1);
+	}
+
+	let entries: Vec<_> = String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.map(|line| line.trim_start_matches("./").trim_end_matches('/').to_string())
+		.filter(|line| !line.is_empty())
+		.collect();
+
+	let mut top_level: Vec<_> = entries.iter()
+		.map(|entry| entry.split('/').next().unwrap())
+		.collect();
+	top_level.sort();
+	top_level.dedup();
+
+	let workdir = if top_level.len() == 1 && entries.iter().any(|entry| entry.contains('/')) {
+		top_level[0].to_string()
+	} else {
+		String::new()
+	};
+
+	let files = entries.iter()
+		.filter_map(|entry| if workdir.is_empty() {
+			Some(entry.as_str())
+		} else {
+			entry.strip_prefix(&workdir).and_then(|entry| entry.strip_prefix('/'))
+		})
+		.filter(|entry| !entry.contains('/'))
+		.map(String::from)
+		.collect();
+
+	(workdir, files)
+}
+
+fn git_version(repo: &Path) -> Option<String> {
+	let output = Command::new("git")
+		.args(["describe", "--tags", "--abbrev=0"])
+		.current_dir(repo)
+		.output()
+		.ok()?;
+	if !output.status.success() {
+		return None;
+	}
+
+	let tag = String::from_utf8(output.stdout).ok()?;
+	let tag = tag.trim();
+	Some(version_from_name(tag).unwrap_or(tag).to_string())
+}
+
+fn quote(value: &str) -> String {
+	Value::String(value.to_string()).to_string()
+}
+
+fn select_template(state: &State, build_system: Option<BuildSystem>) -> Option<String> {
+	let build_system = build_system?;
+
+	let mut names: Vec<_> = state.templates.keys().collect();
+	names.sort();
+
+	names.into_iter()
+		.find(|name| {
+			let name = name.to_lowercase();
+			build_system.template_names().iter().any(|part| name.contains(part))
+		})
+		.cloned()
+}
+
+pub fn new_recipe(state: &State, name: &str, url: &str, template: Option<String>, host: bool) {
+	let recipe_path = recipes_dir(state, host).join(name).join("build.toml");
+	if recipe_path.exists() {
+		output::error(format_args!("{} already exists", recipe_path.display()));
+		exit(1);
+	}
+
+	if !url.contains(".git") && !is_tarball(url) {
+		output::error(format_args!("{} is neither a tar archive nor a git repository", url));
+		exit(1);
+	}
+
+	if let Some(template) = &template && !state.templates.contains_key(template) {
+		output::error(format_args!("use of undefined template {}", template));
+		exit(1);
+	}
+
+	let build_root = std::path::absolute(&state.config.general.build_root)
+		.expect("failed to make build root absolute");
+	let archives_dir = build_root.join("archives");
+	create_dir(&archives_dir);
+
+	let path = archives_dir.join(archive_name(url));
+	if !path.exists() {
+		let Some((mut cmd, method, source)) = fetch_command(url, &path, false) else {
+			output::error(format_args!("unsupported source {}, expected an http(s) or git url", url));
+			exit(1);
+		};
+
+		output::info(format_args!("fetching {} using {}", source, method));
+		let log_path = logs::log_path(&build_root, name, host, "fetch");
+		let status = logs::run(&mut cmd, &logs::open_log(&log_path));
+		if let Some(message) = logs::failure(&status, method) {
+			output::error(format_args!("{}, see {}", message, log_path.display()));
+			if method == "wget" {
+				remove_file(&path);
+			}
+			exit(1);
+		}
+	}
+
+	let (version, workdir, files) = if url.contains(".git") {
+		let files = read_dir(&path)
+			.map(|dir| dir.flatten()
+				.filter_map(|entry| entry.file_name().into_string().ok())
+				.collect())
+			.unwrap_or_default();
+		(git_version(&path), source_to_name(url).to_string(), files)
+	} else {
+		let (workdir, files) = list_archive(&path);
+		let version = version_from_name(strip_archive_extension(source_to_name(url)))
+			.or_else(|| version_from_name(&workdir))
+			.map(String::from);
+		(version, workdir, files)
+	};
+
+	let build_system = BuildSystem::detect(&files);
+	let template = template.or_else(|| select_template(state, build_system));
+
+	let version = version.unwrap_or_else(|| {
+		output::info(format_args!("failed to detect the version of {}, set it in the recipe", name));
+		"0".to_string()
+	});
+	let templatize = |value: &str| if version != "0" {
+		value.replace(&version, "@VERSION@")
+	} else {
+		value.to_string()
+	};
+
+	let mut recipe = format!(
+		"[general]\nname = {}\nversion = {}\nsrc = [{}]\nworkdir = {}\n",
+		quote(name),
+		quote(&version),
+		quote(&templatize(url)),
+		quote(&templatize(&workdir)));
+
+	if let Some(template) = &template {
+		recipe += &format!("template = {}\n", quote(template));
+	} else if let Some(build_system) = build_system {
+		for (step, command) in build_system.steps() {
+			if !command.is_empty() {
+				recipe += &format!("\n[{}]\nargs = [[{}]]\n", step, quote(command));
+			}
+		}
+	}
+
+	create_dir(recipe_path.parent().unwrap());
+	if let Err(e) = write(&recipe_path, recipe) {
+		output::error(format_args!("failed to write {}: {}", recipe_path.display(), e));
+		exit(1);
+	}
+
+	output::info(format_args!(
+		"wrote {} for {} {} using {}{}",
+		recipe_path.display(),
+		display_name(name, host),
+		version,
+		build_system.map_or("an unknown build system", |build_system| build_system.name()),
+		template.map(|template| format!(" and the {} template", template)).unwrap_or_default()));
+}
@@ -1,6 +1,26 @@
 use std::collections::HashMap;
 use serde::Deserialize;
 use toml::Value;
+use crate::build::{self, EnvEntry, NamedStep};
+
+// a command of a template is a single shell string or an argv table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TemplateCommand {
+	Shell(String),
+	Exec {
+		argv: Vec<String>
+	}
+}
+
+impl From<TemplateCommand> for build::Command {
+	fn from(command: TemplateCommand) -> Self {
+		match command {
+			TemplateCommand::Shell(command) => build::Command::Shell(vec![command]),
+			TemplateCommand::Exec { argv } => build::Command::Exec { argv }
+		}
+	}
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Template {
@@ -13,22 +33,26 @@ pub struct Template {
 	pub host_depends: Vec<String>,
 
 	#[serde(default)]
-	pub add_prepare: Vec<String>,
+	pub add_prepare: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub add_configure: Vec<TemplateCommand>,
 	#[serde(default)]
-	pub add_configure: Vec<String>,
+	pub add_build: Vec<TemplateCommand>,
 	#[serde(default)]
-	pub add_build: Vec<String>,
+	pub add_install: Vec<TemplateCommand>,
 	#[serde(default)]
-	pub add_install: Vec<String>,
+	pub add_check: Vec<TemplateCommand>,
 
 	#[serde(default)]
-	pub prepare_env: Vec<HashMap<String, String>>,
+	pub prepare_env: Vec<EnvEntry>,
 	#[serde(default)]
-	pub configure_env: Vec<HashMap<String, String>>,
+	pub configure_env: Vec<EnvEntry>,
 	#[serde(default)]
-	pub build_env: Vec<HashMap<String, String>>,
+	pub build_env: Vec<EnvEntry>,
 	#[serde(default)]
-	pub install_env: Vec<HashMap<String, String>>,
+	pub install_env: Vec<EnvEntry>,
+	#[serde(default)]
+	pub check_env: Vec<EnvEntry>,
 
 	#[serde(default)]
 	pub default_prepare: String,
@@ -38,6 +62,30 @@ pub struct Template {
 	pub default_build: String,
 	#[serde(default)]
 	pub default_install: String,
+	#[serde(default)]
+	pub default_check: String,
+
+	// commands of the hooks, appended to the ones of the recipe
+	#[serde(default)]
+	pub pre_prepare: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub post_prepare: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub pre_configure: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub post_configure: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub pre_build: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub post_build: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub pre_install: Vec<TemplateCommand>,
+	#[serde(default)]
+	pub post_install: Vec<TemplateCommand>,
+
+	// named steps the recipe doesn't define itself
+	#[serde(default)]
+	pub steps: Vec<NamedStep>,
 
 	#[serde(flatten)]
 	pub others: HashMap<String, Value>
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::{create_dir, display_name, load_recipe, output, State};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Timings {
+	#[serde(default)]
+	pub steps: Vec<StepTiming>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StepTiming {
+	pub step: String,
+	// seconds since the unix epoch
+	pub start: f64,
+	pub wall: f64,
+	pub cpu: f64
+}
+
+pub struct Timer {
+	start: Instant,
+	start_unix: f64,
+	start_cpu: f64
+}
+
+// cpu time used by all the child processes that have been waited for so far
+fn children_cpu_time() -> f64 {
+	let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+	if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+		return 0.0;
+	}
+
+	let to_secs = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1_000_000.0;
+	to_secs(usage.ru_utime) + to_secs(usage.ru_stime)
+}
+
+impl Timer {
+	pub fn start() -> Self {
+		Self {
+			start: Instant::now(),
+			start_unix: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map(|time| time.as_secs_f64())
+				.unwrap_or(0.0),
+			start_cpu: children_cpu_time()
+		}
+	}
+
+	pub fn elapsed(&self) -> f64 {
+		self.start.elapsed().as_secs_f64()
+	}
+
+	pub fn finish(&self, step: &str) -> StepTiming {
+		StepTiming {
+			step: step.to_string(),
+			start: self.start_unix,
+			wall: self.elapsed(),
+			cpu: children_cpu_time() - self.start_cpu
+		}
+	}
+}
+
+fn timings_dir(build_root: &Path, host: bool) -> PathBuf {
+	if host {
+		build_root.join("host_timings")
+	} else {
+		build_root.join("timings")
+	}
+}
+
+fn load_timings(path: &Path) -> Timings {
+	match read_to_string(path) {
+		Ok(data) => toml::from_str(&data).unwrap_or_default(),
+		Err(_) => Timings::default()
+	}
+}
+
+// only the latest run of each step is kept
+pub fn record(build_root: &Path, name: &str, host: bool, timing: StepTiming) {
+	let dir = timings_dir(build_root, host);
+	create_dir(&dir);
+
+	let path = dir.join(format!("{}.toml", name));
+	let mut timings = load_timings(&path);
+	timings.steps.retain(|step| step.step != timing.step);
+	timings.steps.push(timing);
+
+	let data = toml::to_string(&timings).expect("failed to serialize timings");
+	if let Err(e) = write(&path, data) {
+		output::error(format_args!("failed to write {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+struct PackageTimings {
+	name: String,
+	host: bool,
+	steps: Vec<StepTiming>,
+	wall: f64,
+	cpu: f64
+}
+
+impl PackageTimings {
+	fn display_name(&self) -> String {
+		display_name(&self.name, self.host)
+	}
+}
+
+fn format_duration(secs: f64) -> String {
+	if secs >= 60.0 {
+		let secs = secs.round() as u64;
+		format!("{}m{:02}s", secs / 60, secs % 60)
+	} else {
+		format!("{:.1}s", secs)
+	}
+}
+
+fn load_all_timings(build_root: &Path) -> Vec<PackageTimings> {
+	let mut packages = Vec::new();
+
+	for host in [false, true] {
+		let dir = match read_dir(timings_dir(build_root, host)) {
+			Ok(dir) => dir,
+			Err(_) => continue
+		};
+
+		for file in dir.flatten() {
+			let path = file.path();
+			if path.extension().is_none_or(|ext| ext != "toml") {
+				continue;
+			}
+
+			let timings = load_timings(&path);
+			packages.push(PackageTimings {
+				name: path.file_stem().unwrap().to_str().unwrap().to_string(),
+				host,
+				wall: timings.steps.iter().map(|step| step.wall).sum(),
+				cpu: timings.steps.iter().map(|step| step.cpu).sum(),
+				steps: timings.steps
+			});
+		}
+	}
+
+	packages
+}
+
+fn package_depends(state: &State, name: &str, host: bool) -> Vec<(String, bool)> {
+	let recipes_dir = if host {
+		&state.config.general.host_recipes_dir
+	} else {
+		&state.config.general.recipes_dir
+	};
+	if !Path::new(recipes_dir).join(name).join("build.toml").exists() {
+		return Vec::new();
+	}
+
+	let recipe = load_recipe(&state.config, name, host);
+
+	if state.config.general.prefer_binaries && !recipe.general.binary_alternative.is_empty() {
+		return vec![(recipe.general.binary_alternative, host)];
+	}
+
+	let mut depends: Vec<_> = recipe.general.depends.into_iter().map(|dep| (dep, false))
+		.chain(recipe.general.host_depends.into_iter().map(|dep| (dep, true)))
+		.collect();
+
+	if let Some(template) = recipe.general.template.and_then(|name| state.templates.get(&name)) {
+		depends.extend(template.depends.iter().map(|dep| (dep.clone(), false)));
+		depends.extend(template.host_depends.iter().map(|dep| (dep.clone(), true)));
+	}
+
+	depends
+}
+
+fn critical_path(state: &State, packages: &[PackageTimings]) -> Vec<usize> {
+	let indices: HashMap<_, _> = packages.iter()
+		.enumerate()
+		.map(|(i, pkg)| ((pkg.name.clone(), pkg.host), i))
+		.collect();
+
+	// the cost and the path of the longest chain of dependencies ending at each package
+	fn visit(
+		key: (String, bool),
+		state: &State,
+		packages: &[PackageTimings],
+		indices: &HashMap<(String, bool), usize>,
+		memo: &mut HashMap<(String, bool), (f64, Vec<usize>)>,
+		visiting: &mut Vec<(String, bool)>) -> (f64, Vec<usize>) {
+		if let Some(result) = memo.get(&key) {
+			return result.clone();
+		}
+		if visiting.contains(&key) {
+			return (0.0, Vec::new());
+		}
+		visiting.push(key.clone());
+
+		let mut best = (0.0, Vec::new());
+		for dep in package_depends(state, &key.0, key.1) {
+			let result = visit(dep, state, packages, indices, memo, visiting);
+			if result.0 > best.0 {
+				best = result;
+			}
+		}
+
+		if let Some(&index) = indices.get(&key) {
+			best.0 += packages[index].wall;
+			best.1.push(index);
+		}
+
+		visiting.pop();
+		memo.insert(key, best.clone());
+		best
+	}
+
+	let mut memo = HashMap::new();
+	let mut best = (0.0, Vec::new());
+	for pkg in packages {
+		let result = visit(
+			(pkg.name.clone(), pkg.host),
+			state,
+			packages,
+			&indices,
+			&mut memo,
+			&mut Vec::new());
+		if result.0 > best.0 {
+			best = result;
+		}
+	}
+
+	best.1
+}
+
+fn write_trace(path: &Path, packages: &[PackageTimings]) {
+	let first_start = packages.iter()
+		.flat_map(|pkg| pkg.steps.iter().map(|step| step.start))
+		.fold(f64::MAX, f64::min);
+
+	let mut events = Vec::new();
+	for (tid, pkg) in packages.iter().enumerate() {
+		events.push(serde_json::json!({
+			"name": "thread_name",
+			"ph": "M",
+			"pid": 1,
+			"tid": tid,
+			"args": { "name": pkg.display_name() }
+		}));
+
+		for step in &pkg.steps {
+			events.push(serde_json::json!({
+				"name": step.step,
+				"cat": pkg.display_name(),
+				"ph": "X",
+				"pid": 1,
+				"tid": tid,
+				"ts": ((step.start - first_start) * 1_000_000.0) as u64,
+				"dur": (step.wall * 1_000_000.0) as u64,
+				"args": { "cpu": step.cpu }
+			}));
+		}
+	}
+
+	let trace = serde_json::json!({ "traceEvents": events });
+	if let Err(e) = write(path, trace.to_string()) {
+		output::error(format_args!("failed to write {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+pub fn report(state: &State) {
+	let build_root = Path::new(&state.config.general.build_root);
+
+	let mut packages = load_all_timings(build_root);
+	if packages.is_empty() {
+		output::error(format_args!("no timings recorded in {}", build_root.display()));
+		exit(1);
+	}
+
+	packages.sort_by(|a, b| b.wall.total_cmp(&a.wall));
+
+	let total: f64 = packages.iter().map(|pkg| pkg.wall).sum();
+	println!("slowest packages (total {}):", format_duration(total));
+	for pkg in packages.iter().take(20) {
+		let steps = pkg.steps.iter()
+			.map(|step| format!("{} {}", step.step, format_duration(step.wall)))
+			.collect::<Vec<_>>()
+			.join(", ");
+		println!(
+			"    {:<32} wall {:>8} cpu {:>8}    {}",
+			pkg.display_name(),
+			format_duration(pkg.wall),
+			format_duration(pkg.cpu),
+			steps);
+	}
+
+	let path = critical_path(state, &packages);
+	let path_total: f64 = path.iter().map(|&i| packages[i].wall).sum();
+	println!("critical path ({}):", format_duration(path_total));
+	for &i in &path {
+		println!("    {:<32} {:>8}", packages[i].display_name(), format_duration(packages[i].wall));
+	}
+
+	let trace_path = build_root.join("trace.json");
+	write_trace(&trace_path, &packages);
+	println!("trace written to {}", trace_path.display());
+}
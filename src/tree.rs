@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{read, write, File};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{exit, Command};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+use crate::{create_dir, output, remove_file, reproducible};
+
+// lists the files that were copied from the source so that the ones removed there can be removed
+// from the build dir without touching the build outputs
+const MANIFEST: &str = "qpkg.copied";
+
+fn source_files(src: &Path) -> impl Iterator<Item = walkdir::DirEntry> {
+	WalkDir::new(src)
+		.min_depth(1)
+		.into_iter()
+		.filter_entry(|entry| entry.file_name() != ".git")
+		.flatten()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+	path.symlink_metadata().ok()?.modified().ok()
+}
+
+// HEAD and a hash of the uncommitted changes of a git source, None for other sources, which only
+// change when they're prepared again
+pub fn state(src: &Path) -> Option<String> {
+	if !src.join(".git").exists() {
+		return None;
+	}
+
+	let git = |args: &[&str]| Command::new("git")
+		.args(args)
+		.current_dir(src)
+		.output()
+		.ok()
+		.filter(|output| output.status.success());
+	let head = git(&["rev-parse", "HEAD"])?;
+	let diff = git(&["diff", "--binary", "HEAD"])?;
+	Some(format!("{} {}", String::from_utf8_lossy(&head.stdout).trim(), reproducible::hash_bytes(&diff.stdout)))
+}
+
+fn remove_dir_all(path: &Path) {
+	if let Err(e) = std::fs::remove_dir_all(path) {
+		output::error(format_args!("failed to remove {}: {}", path.display(), e));
+		exit(1);
+	}
+}
+
+fn same_file(src: &Path, dest: &Path) -> bool {
+	let (Ok(src), Ok(dest)) = (src.symlink_metadata(), dest.symlink_metadata()) else {
+		return false;
+	};
+	src.file_type() == dest.file_type() &&
+		src.len() == dest.len() &&
+		src.modified().ok() == dest.modified().ok()
+}
+
+// copies the files of src that differ to dest and removes the ones that were copied before but
+// are gone from src, returns the amount of changed files
+pub fn sync(src: &Path, dest: &Path) -> usize {
+	create_dir(dest);
+
+	let manifest_path = dest.join(MANIFEST);
+	let copied = read(&manifest_path).unwrap_or_default();
+
+	let mut changed = 0;
+	let mut files = HashSet::new();
+
+	for entry in source_files(src) {
+		let rel = entry.path().strip_prefix(src).unwrap();
+		let target = dest.join(rel);
+		files.insert(rel.to_path_buf());
+
+		// a file that became a dir or the other way around
+		let file_type = entry.file_type();
+		match target.symlink_metadata() {
+			Ok(meta) if meta.is_dir() && !file_type.is_dir() => remove_dir_all(&target),
+			Ok(meta) if !meta.is_dir() && (file_type.is_dir() || !same_file(entry.path(), &target)) => remove_file(&target),
+			_ => {}
+		}
+
+		if file_type.is_dir() {
+			create_dir(&target);
+			continue;
+		}
+		if target.symlink_metadata().is_ok() {
+			continue;
+		}
+
+		let result = if file_type.is_symlink() {
+			std::fs::read_link(entry.path()).and_then(|link| std::os::unix::fs::symlink(link, &target))
+		} else {
+			// the mtime is kept so that the next sync can tell that the file didn't change, setting
+			// it only needs ownership so read-only copies work too
+			std::fs::copy(entry.path(), &target)
+				.and_then(|_| File::open(&target))
+				.and_then(|file| file.set_modified(modified(entry.path()).unwrap_or(SystemTime::now())))
+		};
+		if let Err(e) = result {
+			output::error(format_args!("failed to copy {} to {}: {}", entry.path().display(), target.display(), e));
+			exit(1);
+		}
+		changed += 1;
+	}
+
+	// the paths are separated by nul since they can contain anything else
+	let copied = copied.split(|byte| *byte == 0)
+		.filter(|rel| !rel.is_empty())
+		.map(|rel| Path::new(OsStr::from_bytes(rel)))
+		.filter(|rel| !files.contains(*rel));
+	for rel in copied {
+		let target = dest.join(rel);
+		if target.symlink_metadata().is_ok_and(|meta| !meta.is_dir()) {
+			remove_file(&target);
+			changed += 1;
+		}
+	}
+
+	let mut manifest: Vec<_> = files.into_iter().collect();
+	manifest.sort();
+	let manifest: Vec<u8> = manifest.iter()
+		.flat_map(|rel| rel.as_os_str().as_bytes().iter().chain(&[0]))
+		.copied()
+		.collect();
+	if let Err(e) = write(&manifest_path, manifest) {
+		output::error(format_args!("failed to write {}: {}", manifest_path.display(), e));
+		exit(1);
+	}
+
+	changed
+}
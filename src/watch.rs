@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::time::{Duration, Instant};
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use walkdir::WalkDir;
+use crate::cli::{RunArgs, WatchArgs};
+use crate::output::{self, Format};
+use crate::{display_name, load_finalized_recipe, load_recipe, package_dirs, recipes_dir, State};
+
+const MASK: WatchMask = WatchMask::MODIFY
+	.union(WatchMask::CLOSE_WRITE)
+	.union(WatchMask::CREATE)
+	.union(WatchMask::DELETE)
+	.union(WatchMask::MOVED_FROM)
+	.union(WatchMask::MOVED_TO);
+
+// the arguments that are passed on to the qpkg commands that do the work
+pub struct Invocation<'a> {
+	pub config_path: &'a str,
+	pub env: &'a [(String, String)],
+	pub run: RunArgs
+}
+
+impl Invocation<'_> {
+	fn command(&self, op: &[&str], host: bool, names: &[String]) -> Command {
+		let exe = std::env::current_exe().expect("failed to get the path of qpkg");
+		let mut cmd = Command::new(exe);
+		cmd.args(["--config", self.config_path]);
+		if output::format() == Format::Json {
+			cmd.args(["--output", "json"]);
+		}
+		if host {
+			cmd.arg("--host");
+		}
+		for (name, value) in self.env {
+			cmd.arg(format!("--env={}={}", name, value));
+		}
+
+		cmd.args(op);
+		let RunArgs { dev, sandbox, shell_on_failure, keep_going } = self.run;
+		for (flag, set) in [("--dev", dev), ("--sandbox", sandbox), ("--shell-on-failure", shell_on_failure), ("--keep-going", keep_going)] {
+			if set {
+				cmd.arg(flag);
+			}
+		}
+		cmd.args(names);
+		cmd
+	}
+
+	// the output of the child is shown as it happens
+	fn run(&self, op: &[&str], host: bool, names: &[String]) -> bool {
+		match self.command(op, host, names).status() {
+			Ok(status) => status.success(),
+			Err(e) => {
+				output::error(format_args!("failed to spawn qpkg: {}", e));
+				exit(1);
+			}
+		}
+	}
+}
+
+struct Watcher {
+	inotify: Inotify,
+	// the package each watched dir belongs to, the dir and whether it's the recipe dir
+	watches: HashMap<WatchDescriptor, (String, PathBuf, bool)>,
+	// the packages whose recipe changed, their workdir may be somewhere else after the rebuild
+	recipe_changed: Vec<String>
+}
+
+impl Watcher {
+	fn add(&mut self, name: &str, dir: &Path, recipe: bool) {
+		match self.inotify.watches().add(dir, MASK) {
+			Ok(wd) => {
+				self.watches.insert(wd, (name.to_string(), dir.to_path_buf(), recipe));
+			},
+			Err(e) => {
+				output::error(format_args!("failed to watch {}: {}", dir.display(), e));
+				exit(1);
+			}
+		}
+	}
+
+	fn add_tree(&mut self, name: &str, dir: &Path) {
+		let dirs: Vec<_> = WalkDir::new(dir)
+			.follow_links(true)
+			.into_iter()
+			.filter_entry(|entry| entry.file_name() != ".git")
+			.flatten()
+			.filter(|entry| entry.file_type().is_dir())
+			.map(|entry| entry.into_path())
+			.collect();
+		for dir in dirs {
+			self.add(name, &dir, false);
+		}
+	}
+
+	fn watch_package(&mut self, state: &State, name: &str, host: bool) {
+		let recipe = load_finalized_recipe(state, name, host);
+		let [root_src_dir, _, _] = package_dirs(Path::new(&state.config.general.build_root), name, host);
+
+		self.add(name, &recipes_dir(state, host).join(name), true);
+		self.add_tree(name, &root_src_dir.join(&recipe.general.workdir));
+	}
+
+	// watches the workdir the recipe now resolves to instead of the old one
+	fn rewatch_tree(&mut self, state: &State, name: &str, host: bool) {
+		let old: Vec<_> = self.watches.iter()
+			.filter(|(_, (pkg, _, recipe))| pkg == name && !recipe)
+			.map(|(wd, _)| wd.clone())
+			.collect();
+		for wd in old {
+			self.watches.remove(&wd);
+			// fails when the dir is already gone
+			self.inotify.watches().remove(wd).ok();
+		}
+
+		let recipe = load_finalized_recipe(state, name, host);
+		let [root_src_dir, _, _] = package_dirs(Path::new(&state.config.general.build_root), name, host);
+		self.add_tree(name, &root_src_dir.join(&recipe.general.workdir));
+	}
+
+	// returns the packages with changes, new dirs are watched as they appear
+	fn read(&mut self, buffer: &mut [u8], blocking: bool) -> Vec<String> {
+		let events = if blocking {
+			self.inotify.read_events_blocking(buffer)
+		} else {
+			self.inotify.read_events(buffer)
+		};
+		let events = match events {
+			Ok(events) => events,
+			Err(e) if e.kind() == ErrorKind::WouldBlock => return Vec::new(),
+			Err(e) => {
+				output::error(format_args!("failed to read inotify events: {}", e));
+				exit(1);
+			}
+		};
+
+		let mut changed = Vec::new();
+		let mut new_dirs = Vec::new();
+		for event in events {
+			// the dir was removed or is no longer watched
+			if event.mask.contains(inotify::EventMask::IGNORED) {
+				self.watches.remove(&event.wd);
+				continue;
+			}
+
+			let Some((name, dir, recipe)) = self.watches.get(&event.wd) else {
+				continue;
+			};
+			// the stamps and logs qpkg writes itself shouldn't start another build
+			if event.name.is_some_and(|file| file.to_str().is_some_and(|file| file.starts_with("qpkg.")) || file == OsStr::new(".git")) {
+				continue;
+			}
+
+			if event.mask.contains(inotify::EventMask::ISDIR) && event.mask.contains(inotify::EventMask::CREATE) &&
+				let Some(file) = event.name {
+				new_dirs.push((name.clone(), dir.join(file)));
+			}
+			if !changed.contains(name) {
+				changed.push(name.clone());
+			}
+			if *recipe && !self.recipe_changed.contains(name) {
+				self.recipe_changed.push(name.clone());
+			}
+		}
+
+		for (name, dir) in new_dirs {
+			self.add_tree(&name, &dir);
+		}
+		changed
+	}
+}
+
+// the packages that depend on name directly or indirectly and were built before, host packages
+// can be needed by both kinds of packages
+fn reverse_depends(state: &State, name: &str, host: bool) -> Vec<(String, bool)> {
+	let build_root = Path::new(&state.config.general.build_root);
+
+	let mut recipes = Vec::new();
+	for recipe_host in [false, true] {
+		let Ok(entries) = std::fs::read_dir(recipes_dir(state, recipe_host)) else {
+			continue;
+		};
+		for entry in entries.flatten() {
+			if entry.path().join("build.toml").exists() && let Ok(pkg) = entry.file_name().into_string() {
+				let recipe = load_recipe(&state.config, &pkg, recipe_host);
+				recipes.push((pkg, recipe_host, recipe.general.depends, recipe.general.host_depends));
+			}
+		}
+	}
+
+	let mut found: Vec<(String, bool)> = Vec::new();
+	let mut queue = vec![(name.to_string(), host)];
+	while let Some((dep, dep_host)) = queue.pop() {
+		for (pkg, pkg_host, depends, host_depends) in &recipes {
+			// target packages only depend on host packages through host_depends
+			let needs = if dep_host && !pkg_host {
+				host_depends.contains(&dep)
+			} else {
+				*pkg_host == dep_host && depends.contains(&dep)
+			};
+			let key = (pkg.clone(), *pkg_host);
+			if needs && !found.contains(&key) && (pkg != name || *pkg_host != host) {
+				found.push(key.clone());
+				queue.push(key);
+			}
+		}
+	}
+
+	found.retain(|(pkg, pkg_host)| package_dirs(build_root, pkg, *pkg_host)[1].join("qpkg.built").exists());
+	found
+}
+
+fn rebuild(state: &State, invocation: &Invocation, name: &str, host: bool, reverse_deps: bool) {
+	output::info(format_args!("rebuilding {}", display_name(name, host)));
+	if !invocation.run(&["rebuild"], host, &[name.to_string()]) {
+		output::error(format_args!("rebuilding {} failed, waiting for the next change", display_name(name, host)));
+		return;
+	}
+
+	if reverse_deps {
+		let dependents = reverse_depends(state, name, host);
+		for kind in [true, false] {
+			let names: Vec<_> = dependents.iter()
+				.filter(|(_, dep_host)| *dep_host == kind)
+				.map(|(dep, _)| dep.clone())
+				.collect();
+			if names.is_empty() {
+				continue;
+			}
+
+			output::info(format_args!("rebuilding the dependents {}", names.join(" ")));
+			if !invocation.run(&["rebuild"], kind, &names) {
+				output::error(format_args!("rebuilding the dependents of {} failed, waiting for the next change", display_name(name, host)));
+				return;
+			}
+		}
+	}
+
+	output::info(format_args!("{} is up to date", display_name(name, host)));
+}
+
+pub fn watch(state: &State, invocation: &Invocation, host: bool, args: &WatchArgs) {
+	let names = &args.packages.names;
+
+	// prepares the workdirs that are watched
+	if !invocation.run(&["install", "--sync"], host, names) {
+		output::error("the first build failed, fix it before watching");
+		exit(1);
+	}
+
+	let inotify = match Inotify::init() {
+		Ok(inotify) => inotify,
+		Err(e) => {
+			output::error(format_args!("failed to initialize inotify: {}", e));
+			exit(1);
+		}
+	};
+	let mut watcher = Watcher { inotify, watches: HashMap::new(), recipe_changed: Vec::new() };
+
+	for name in names {
+		watcher.watch_package(state, name, host);
+	}
+
+	output::info(format_args!("watching {}, press ctrl-c to stop", names.join(" ")));
+
+	let debounce = Duration::from_millis(args.debounce);
+	let mut buffer = [0; 4096];
+	loop {
+		let mut changed = watcher.read(&mut buffer, true);
+
+		// editors and builds touch many files in a row so wait until it's quiet
+		let mut last_change = Instant::now();
+		while last_change.elapsed() < debounce {
+			std::thread::sleep(Duration::from_millis(50));
+			let more = watcher.read(&mut buffer, false);
+			if !more.is_empty() {
+				last_change = Instant::now();
+			}
+			for name in more {
+				if !changed.contains(&name) {
+					changed.push(name);
+				}
+			}
+		}
+
+		for name in &changed {
+			rebuild(state, invocation, name, host, args.reverse_deps);
+		}
+		for name in std::mem::take(&mut watcher.recipe_changed) {
+			watcher.rewatch_tree(state, &name, host);
+		}
+
+		// the rebuilds can cause events of their own, like in-tree build outputs
+		while !watcher.read(&mut buffer, false).is_empty() {}
+	}
+}